@@ -0,0 +1,265 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs::{self, Permissions};
+
+use relative_path::{RelativePath, RelativePathBuf};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::deployment::DeploymentRecord;
+use crate::{
+    DeployError, DeployMethod, FileStatus, Hash, LockMode, MediaArchive, StoreFileError, StoreMethod, StoreOutcome,
+};
+
+impl MediaArchive {
+    /// Stores the files in the deployment directory that weren't deployed by the archive,
+    /// or that were modified or replaced since being deployed, and records them as deployed.
+    ///
+    /// If `options.replace_with` is set, the adopted files are then replaced in place by the stored files,
+    /// deployed with the given method. Otherwise, they are left as they are, and recorded as copies.
+    /// Symlinks are never adopted.
+    ///
+    /// Failing to adopt a file doesn't abort the operation. Instead, the result of adopting
+    /// each file is collected in the returned [`AdoptReport`].
+    #[tracing::instrument(skip(self), err)]
+    pub fn adopt(&self, options: AdoptOptions) -> Result<AdoptReport, DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        let status = self.status()?;
+
+        let mut report = AdoptReport::default();
+        let mut stored = Vec::new();
+        for (path, status) in status.files {
+            if !matches!(
+                status,
+                Ok(FileStatus::Untracked | FileStatus::Modified | FileStatus::ReplacedSymlink)
+            ) {
+                continue;
+            }
+
+            match self.store_adopted_file(&path) {
+                Ok(Some((outcome, permissions))) => stored.push((path, outcome, permissions)),
+                Ok(None) => (),
+                Err(err) => {
+                    warn!("failed to adopt '{}': {}", path, err);
+                    report.files.insert(path, Err(err));
+                }
+            }
+        }
+
+        let method = options.replace_with.unwrap_or(DeployMethod::Copy);
+        let journal = self.begin_deploy(
+            stored
+                .iter()
+                .map(|(path, outcome, _)| (path.as_relative_path(), outcome.hash(), method)),
+        )?;
+        let mut state = self.load_deployment_state()?;
+        for (path, outcome, permissions) in stored {
+            let result = match options.replace_with {
+                Some(method) => self.replace_adopted_file(&path, &outcome.hash(), permissions, method),
+                None => Ok(DeployMethod::Copy),
+            };
+            match result {
+                Ok(method) => {
+                    state
+                        .files
+                        .insert(path.clone(), DeploymentRecord::new(outcome.hash(), method));
+                    report.files.insert(path, Ok(outcome));
+                }
+                Err(err) => {
+                    warn!("failed to adopt '{}': {}", path, err);
+                    report.files.insert(path, Err(err));
+                }
+            }
+        }
+        self.save_deployment_state(&state)?;
+        journal.complete();
+
+        info!(
+            "adopted {} files, {} files failed to be adopted",
+            report.files.len() - report.errors().count(),
+            report.errors().count()
+        );
+        Ok(report)
+    }
+
+    /// Stores a file in the deployment directory, and returns the outcome and the file's permissions.
+    ///
+    /// Returns `None` if the file is a symlink.
+    fn store_adopted_file(&self, path: &RelativePath) -> Result<Option<(StoreOutcome, Permissions)>, AdoptError> {
+        let target_path = self.deploy_target_path(path).map_err(AdoptError::Deploy)?;
+        let metadata = target_path.symlink_metadata().map_err(|err| {
+            AdoptError::Deploy(DeployError::Metadata {
+                path: target_path.clone(),
+                source: err,
+            })
+        })?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+
+        let outcome = self
+            .store_file(&target_path, StoreMethod::Copy)
+            .map_err(AdoptError::Store)?;
+        Ok(Some((outcome, metadata.permissions())))
+    }
+
+    /// Replaces an adopted file by the stored file, and returns the method it was deployed with.
+    fn replace_adopted_file(
+        &self,
+        path: &RelativePath,
+        hash: &Hash,
+        permissions: Permissions,
+        method: DeployMethod,
+    ) -> Result<DeployMethod, AdoptError> {
+        let target_path = self.deploy_target_path(path).map_err(AdoptError::Deploy)?;
+        let method = self
+            .replace_deployed_file(hash, &target_path, method)
+            .map_err(AdoptError::Deploy)?;
+        if matches!(method, DeployMethod::Copy | DeployMethod::Reflink) {
+            if let Err(err) = fs::set_permissions(&target_path, permissions) {
+                warn!("failed to set permissions of file '{}': {}", target_path.display(), err);
+            }
+        }
+        Ok(method)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AdoptOptions {
+    /// How to deploy the adopted files in place of the original files, if they should be replaced.
+    pub replace_with: Option<DeployMethod>,
+}
+
+/// The result of adopting files with [`MediaArchive::adopt`].
+#[derive(Debug, Default)]
+pub struct AdoptReport {
+    /// The result of storing each adopted file, indexed by its path relative to the deployment directory.
+    pub files: BTreeMap<RelativePathBuf, Result<StoreOutcome, AdoptError>>,
+}
+
+impl AdoptReport {
+    /// Returns an iterator over the files that failed to be adopted.
+    pub fn errors(&self) -> impl Iterator<Item = (&RelativePath, &AdoptError)> {
+        self.files
+            .iter()
+            .filter_map(|(path, result)| result.as_ref().err().map(|err| (path.as_relative_path(), err)))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AdoptError {
+    #[error(transparent)]
+    Deploy(DeployError),
+    #[error("failed to store file: {0}")]
+    Store(#[source] StoreFileError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+
+    use crate::deploy::set_mode;
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::DiskStructure;
+
+    #[test]
+    fn adopt() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        archive
+            .deploy_file(&hash, RelativePath::new("modified.txt"), DeployMethod::Copy)
+            .unwrap();
+        set_mode(temp_dir.child("modified.txt").path(), 0o644);
+        temp_dir.child("modified.txt").write_str("modified data").unwrap();
+        temp_dir.child("a/new.txt").write_str(TEST_DATA).unwrap();
+        temp_dir.child("link.txt").symlink_to_file("a/new.txt").unwrap();
+
+        let report = archive.adopt(AdoptOptions::default()).unwrap();
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(report.files.len(), 2);
+        assert!(matches!(
+            report.files[RelativePath::new("a/new.txt")],
+            Ok(StoreOutcome::Deduplicated(h)) if h == hash
+        ));
+        let modified_hash = blake3::hash("modified data".as_bytes());
+        assert!(matches!(
+            report.files[RelativePath::new("modified.txt")],
+            Ok(StoreOutcome::Stored(h) | StoreOutcome::Reflinked(h)) if h == modified_hash
+        ));
+        assert!(archive.contains(&modified_hash));
+        assert_eq!(
+            archive
+                .deployment(RelativePath::new("modified.txt"))
+                .unwrap()
+                .unwrap()
+                .hash,
+            modified_hash
+        );
+
+        let status = archive.status().unwrap();
+        assert_eq!(status.files.len(), 1);
+        assert!(matches!(
+            status.files[RelativePath::new("link.txt")],
+            Ok(FileStatus::Untracked)
+        ));
+    }
+
+    #[test]
+    fn adopt_replaced_symlink() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        archive
+            .deploy_file(&hash, RelativePath::new("a.txt"), DeployMethod::Symlink)
+            .unwrap();
+        fs::remove_file(temp_dir.child("a.txt")).unwrap();
+        temp_dir.child("a.txt").write_str("edited data").unwrap();
+
+        let report = archive.adopt(AdoptOptions::default()).unwrap();
+        let edited_hash = blake3::hash("edited data".as_bytes());
+        assert!(matches!(
+            report.files[RelativePath::new("a.txt")],
+            Ok(StoreOutcome::Stored(h) | StoreOutcome::Reflinked(h)) if h == edited_hash
+        ));
+        let record = archive.deployment(RelativePath::new("a.txt")).unwrap().unwrap();
+        assert_eq!(record.hash, edited_hash);
+        assert_eq!(record.method, DeployMethod::Copy);
+        assert!(archive.status().unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn adopt_replace_with_hardlinks() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        temp_dir.child("a.txt").write_str(TEST_DATA).unwrap();
+
+        let report = archive
+            .adopt(AdoptOptions {
+                replace_with: Some(DeployMethod::Hardlink),
+            })
+            .unwrap();
+        let hash = report.files[RelativePath::new("a.txt")].as_ref().unwrap().hash();
+
+        temp_dir.child("a.txt").assert(TEST_DATA);
+        let record = archive.deployment(RelativePath::new("a.txt")).unwrap().unwrap();
+        assert_eq!(record.method, DeployMethod::Hardlink);
+        assert_eq!(
+            file_id::get_file_id(temp_dir.child("a.txt")).unwrap(),
+            file_id::get_file_id(archive.blob_path(&hash).unwrap()).unwrap()
+        );
+    }
+}
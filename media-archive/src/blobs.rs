@@ -0,0 +1,228 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::{self, DirEntry, ReadDir};
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use thiserror::Error;
+
+use crate::{Hash, MediaArchive, STORE_DIRECTORY};
+
+impl MediaArchive {
+    /// Returns an iterator over the files stored in the archive.
+    ///
+    /// Files inside the store directory that aren't stored files, either because their name
+    /// isn't a hash, or because they aren't in the directory their hash says they should be in,
+    /// are skipped, and reported as errors.
+    #[must_use]
+    pub fn blobs(&self) -> Blobs<'_> {
+        Blobs {
+            archive: self,
+            pending_directories: vec![(self.archive_path.join(STORE_DIRECTORY), 0)],
+            current_directory: None,
+        }
+    }
+}
+
+/// An iterator over the files stored in an archive.
+///
+/// Created by [`MediaArchive::blobs`].
+#[derive(Debug)]
+pub struct Blobs<'a> {
+    archive: &'a MediaArchive,
+    pending_directories: Vec<(PathBuf, usize)>,
+    current_directory: Option<(PathBuf, ReadDir, usize)>,
+}
+
+impl Blobs<'_> {
+    /// Returns how deep inside the store directory stored files are.
+    ///
+    /// While the store is being migrated to a different layout, files may be in either layout's depth.
+    fn max_depth(&self) -> usize {
+        let config = &self.archive.config;
+        config
+            .previous_store_layout
+            .map_or(config.store_layout.subdir_count, |previous_layout| {
+                previous_layout.subdir_count.max(config.store_layout.subdir_count)
+            })
+    }
+
+    fn blob_info(&self, entry: &DirEntry) -> Result<BlobInfo, ListBlobsError> {
+        let path = entry.path();
+        let metadata = entry.metadata().map_err(|err| ListBlobsError::Metadata {
+            path: path.clone(),
+            source: err,
+        })?;
+        if !metadata.is_file() {
+            return Err(ListBlobsError::StrayFile(path));
+        }
+
+        let Some(hash) = entry
+            .file_name()
+            .to_str()
+            .and_then(|file_name| Hash::from_hex(file_name).ok())
+        else {
+            return Err(ListBlobsError::StrayFile(path));
+        };
+        if self.archive.get_path_of_stored_file(&hash) != path {
+            return Err(ListBlobsError::Misplaced { path, hash });
+        }
+
+        let modified = metadata.modified().map_err(|err| ListBlobsError::Metadata {
+            path: path.clone(),
+            source: err,
+        })?;
+
+        Ok(BlobInfo {
+            hash,
+            len: metadata.len(),
+            modified,
+        })
+    }
+}
+
+impl Iterator for Blobs<'_> {
+    type Item = Result<BlobInfo, ListBlobsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((directory, entries, depth)) = &mut self.current_directory else {
+                let (directory, depth) = self.pending_directories.pop()?;
+                match fs::read_dir(&directory) {
+                    Ok(entries) => self.current_directory = Some((directory, entries, depth)),
+                    // The store directory is only created when the first file is stored.
+                    Err(err) if err.kind() == io::ErrorKind::NotFound && depth == 0 => (),
+                    Err(err) => {
+                        return Some(Err(ListBlobsError::ReadDir {
+                            path: directory,
+                            source: err,
+                        }))
+                    }
+                }
+                continue;
+            };
+
+            let entry = match entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    return Some(Err(ListBlobsError::ReadDir {
+                        path: directory.clone(),
+                        source: err,
+                    }))
+                }
+                None => {
+                    self.current_directory = None;
+                    continue;
+                }
+            };
+
+            let depth = *depth;
+            if depth == self.max_depth() {
+                return Some(self.blob_info(&entry));
+            }
+
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => self.pending_directories.push((entry.path(), depth + 1)),
+                Ok(_) => return Some(self.blob_info(&entry)),
+                Err(err) => {
+                    return Some(Err(ListBlobsError::Metadata {
+                        path: entry.path(),
+                        source: err,
+                    }))
+                }
+            }
+        }
+    }
+}
+
+/// Information about a file stored in an archive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlobInfo {
+    /// The hash of the file.
+    pub hash: Hash,
+    /// The size of the file, in bytes.
+    pub len: u64,
+    /// The last modification time of the file.
+    pub modified: SystemTime,
+}
+
+#[derive(Debug, Error)]
+pub enum ListBlobsError {
+    #[error("stored file with hash '{hash}' is in the wrong location: '{path}'")]
+    Misplaced { path: PathBuf, hash: Hash },
+    #[error("failed to get file metadata of file '{path}': {source}")]
+    Metadata { path: PathBuf, source: io::Error },
+    #[error("failed to read directory '{path}': {source}")]
+    ReadDir { path: PathBuf, source: io::Error },
+    #[error("'{0}' is not a stored file")]
+    StrayFile(PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+
+    use crate::tests::{temp_media_archive, TEST_DATA, TEST_DATA_HASH};
+    use crate::DiskStructure;
+
+    #[test]
+    fn list_blobs() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        assert_eq!(archive.blobs().count(), 0);
+
+        let first_hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let second_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+
+        let store = temp_dir.child(STORE_DIRECTORY);
+        store.child("stray.txt").touch().unwrap();
+        store.child("aa/bb/not-a-hash").touch().unwrap();
+        store.child("aa/bb").child(TEST_DATA_HASH).touch().unwrap();
+
+        let mut blobs = Vec::new();
+        let mut stray_files = Vec::new();
+        let mut misplaced_files = Vec::new();
+        for result in archive.blobs() {
+            match result {
+                Ok(blob) => blobs.push(blob),
+                Err(ListBlobsError::StrayFile(path)) => stray_files.push(path),
+                Err(ListBlobsError::Misplaced { path, .. }) => misplaced_files.push(path),
+                Err(err) => panic!("unexpected error: {}", err),
+            }
+        }
+
+        blobs.sort_by_key(|blob| blob.len);
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs[0].hash, first_hash);
+        assert_eq!(blobs[0].len, TEST_DATA.len() as u64);
+        assert_eq!(blobs[1].hash, second_hash);
+
+        stray_files.sort();
+        assert_eq!(
+            stray_files,
+            [
+                store.child("aa/bb/not-a-hash").to_path_buf(),
+                store.child("stray.txt").to_path_buf()
+            ]
+        );
+        assert_eq!(
+            misplaced_files,
+            [store.child("aa/bb").child(TEST_DATA_HASH).to_path_buf()]
+        );
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+use crate::{DeploymentTarget, HashAlgorithm, LockError, LockMode, MediaArchive, StoreLayout};
+
+pub(crate) const CONFIG_FILE: &str = "config.json";
+/// The version of the archive's on-disk format, recorded in the configuration file.
+const FORMAT_VERSION: u32 = 1;
+
+impl MediaArchive {
+    /// Returns the configuration of the archive.
+    #[must_use]
+    pub fn config(&self) -> &ArchiveConfig {
+        &self.config
+    }
+
+    /// Changes the configuration of the archive, and saves it inside the archive directory.
+    ///
+    /// The store layout can't be changed this way, since stored files need to be moved to match it,
+    /// use [`MediaArchive::migrate_layout`] instead.
+    #[tracing::instrument(skip(self), err)]
+    pub fn set_config(&mut self, config: ArchiveConfig) -> Result<(), ConfigError> {
+        if config.store_layout != self.config.store_layout
+            || config.previous_store_layout != self.config.previous_store_layout
+        {
+            return Err(ConfigError::StoreLayoutChanged);
+        }
+        check_digest_algorithms(&config)?;
+        self.save_config(config)
+    }
+
+    /// Saves the configuration of the archive inside the archive directory, without any checks.
+    pub(crate) fn save_config(&mut self, config: ArchiveConfig) -> Result<(), ConfigError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(ConfigError::Lock)?;
+        let contents = serde_json::to_vec_pretty(&ConfigFile {
+            version: FORMAT_VERSION,
+            config: config.clone(),
+        })
+        .expect("config serialization should not fail");
+        self.write_file_atomically(&self.archive_path.join(CONFIG_FILE), &contents)
+            .map_err(ConfigError::Write)?;
+
+        self.config = config;
+        info!("saved configuration successfully");
+        Ok(())
+    }
+}
+
+/// Reads the configuration of the archive in `archive_path`.
+///
+/// Returns `None` if there is no configuration file, which means `archive_path` isn't an archive directory.
+pub(crate) fn load_config(archive_path: &Path) -> Result<Option<ArchiveConfig>, ConfigError> {
+    let file = match File::open(archive_path.join(CONFIG_FILE)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(ConfigError::Read(err)),
+    };
+
+    let config_file: ConfigFile = serde_json::from_reader(BufReader::new(file)).map_err(ConfigError::Parse)?;
+    if config_file.version != FORMAT_VERSION {
+        return Err(ConfigError::UnsupportedVersion(config_file.version));
+    }
+    let config = &config_file.config;
+    for layout in std::iter::once(&config.store_layout).chain(&config.previous_store_layout) {
+        if !layout.is_valid() {
+            return Err(ConfigError::InvalidStoreLayout(*layout));
+        }
+    }
+    check_digest_algorithms(config)?;
+    Ok(Some(config_file.config))
+}
+
+/// Fails if the configuration asks for digests with the algorithm stored files are identified by.
+fn check_digest_algorithms(config: &ArchiveConfig) -> Result<(), ConfigError> {
+    if config.digest_algorithms.contains(&HashAlgorithm::Blake3) {
+        return Err(ConfigError::InvalidDigestAlgorithm(HashAlgorithm::Blake3));
+    }
+    Ok(())
+}
+
+/// The configuration of an archive, stored inside the archive directory.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// The directory media files are deployed to, for archives opened with
+    /// [`DiskStructure::Detached`](crate::DiskStructure::Detached).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deploy_path: Option<PathBuf>,
+    /// The kind of symlinks created when deploying files with [`DeployMethod::Symlink`](crate::DeployMethod::Symlink).
+    pub symlink_style: SymlinkStyle,
+    /// How stored files are spread across subdirectories of the store directory.
+    pub store_layout: StoreLayout,
+    /// The store layout stored files are being moved from, if [`MediaArchive::migrate_layout`]
+    /// was interrupted. Files that weren't moved yet are still found in their location in this layout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_store_layout: Option<StoreLayout>,
+    /// The hash algorithms whose digests are computed for every stored file, in addition to its hash,
+    /// so that stored files can be found by them with [`MediaArchive::lookup`].
+    ///
+    /// Stored files are always identified by their BLAKE3 hash, which can't be changed, so
+    /// [`HashAlgorithm::Blake3`] isn't allowed here.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub digest_algorithms: Vec<HashAlgorithm>,
+    /// The named deployment targets, see [`MediaArchive::target`].
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub targets: BTreeMap<String, DeploymentTarget>,
+}
+
+/// The kind of symlinks created when deploying files with [`DeployMethod::Symlink`](crate::DeployMethod::Symlink).
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SymlinkStyle {
+    /// Symlinks point to the stored file with a path relative to the symlink's directory.
+    ///
+    /// The symlinks keep working if the archive is moved as a whole.
+    #[default]
+    Relative,
+    /// Symlinks point to the stored file with an absolute path.
+    ///
+    /// The symlinks keep working if the deployment directory is moved or exported on its own.
+    Absolute,
+    /// Symlinks point to the stored file inside `root`, which is where the store directory
+    /// is reachable from wherever the symlinks are followed, such as from an NFS client
+    /// or from another mount of the store.
+    ///
+    /// The archive itself doesn't need to be able to follow the symlinks.
+    Rooted { root: PathBuf },
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConfigFile {
+    version: u32,
+    #[serde(flatten)]
+    config: ArchiveConfig,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("{0:?} can't be used as a digest algorithm, since stored files are already identified by it")]
+    InvalidDigestAlgorithm(HashAlgorithm),
+    #[error("store layout {0:?} is not valid")]
+    InvalidStoreLayout(StoreLayout),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("failed to parse configuration file: {0}")]
+    Parse(#[source] serde_json::Error),
+    #[error("failed to read configuration file: {0}")]
+    Read(#[source] io::Error),
+    #[error("the store layout can only be changed by migrating the store")]
+    StoreLayoutChanged,
+    #[error("archive format version {0} is not supported")]
+    UnsupportedVersion(u32),
+    #[error("failed to write configuration file: {0}")]
+    Write(#[source] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::temp_media_archive;
+    use crate::{DiskStructure, OpenMediaArchiveError};
+
+    #[test]
+    fn set_config() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Bare);
+        assert_eq!(archive.config(), &ArchiveConfig::default());
+
+        let config = ArchiveConfig {
+            symlink_style: SymlinkStyle::Rooted {
+                root: PathBuf::from("/mnt/archive/store"),
+            },
+            ..ArchiveConfig::default()
+        };
+        archive.set_config(config.clone()).unwrap();
+        assert_eq!(archive.config(), &config);
+
+        let mut archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        assert_eq!(archive.config(), &config);
+
+        let invalid_config = ArchiveConfig {
+            digest_algorithms: vec![HashAlgorithm::Blake3],
+            ..config.clone()
+        };
+        assert!(matches!(
+            archive.set_config(invalid_config),
+            Err(ConfigError::InvalidDigestAlgorithm(HashAlgorithm::Blake3))
+        ));
+        assert_eq!(archive.config(), &config);
+    }
+
+    #[test]
+    fn open_with_invalid_config() {
+        let (temp_dir, _archive) = temp_media_archive(DiskStructure::Bare);
+        std::fs::write(temp_dir.join(CONFIG_FILE), r#"{"version": 2}"#).unwrap();
+
+        assert!(matches!(
+            MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare),
+            Err(OpenMediaArchiveError::Config(ConfigError::UnsupportedVersion(2)))
+        ));
+
+        std::fs::write(
+            temp_dir.join(CONFIG_FILE),
+            r#"{"version": 1, "digest_algorithms": ["sha256", "blake3"]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare),
+            Err(OpenMediaArchiveError::Config(ConfigError::InvalidDigestAlgorithm(
+                HashAlgorithm::Blake3
+            )))
+        ));
+    }
+}
@@ -0,0 +1,519 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Self-describing identifiers of file contents, and lookup of stored files by digests
+//! other than their hash.
+
+use std::fmt::{self, Display, Write as _};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Digest;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{Hash, MediaArchive, COPY_BUFFER_SIZE};
+
+const DIGESTS_DIRECTORY: &str = "digests";
+
+impl MediaArchive {
+    /// Returns the hash of the stored file with the given content identifier, if there is one.
+    ///
+    /// Identifiers using an algorithm other than [`HashAlgorithm::Blake3`] can only be looked up
+    /// if the algorithm was in [`ArchiveConfig::digest_algorithms`](crate::ArchiveConfig::digest_algorithms)
+    /// when the file was stored.
+    pub fn lookup(&self, id: &ContentId) -> Result<Option<Hash>, LookupError> {
+        if id.digest.len() != id.algorithm.digest_len() {
+            return Ok(None);
+        }
+        let hash = match id.algorithm {
+            HashAlgorithm::Blake3 => match id.digest.as_slice().try_into() {
+                Ok(digest) => Hash::from_bytes(digest),
+                Err(_) => return Ok(None),
+            },
+            algorithm @ (HashAlgorithm::Sha256 | HashAlgorithm::Sha512) => {
+                let path = self.digest_index_path(algorithm, &id.digest);
+                let contents = match fs::read_to_string(&path) {
+                    Ok(contents) => contents,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(LookupError::Read { path, source: err }),
+                };
+                Hash::from_hex(contents.trim()).map_err(|_| LookupError::Corrupt(path))?
+            }
+        };
+        // The file may have been garbage collected since its digests were recorded.
+        Ok(self.contains(&hash).then_some(hash))
+    }
+
+    /// Returns the content identifiers of the stored file with the given hash: its hash,
+    /// followed by its digests with every algorithm in
+    /// [`ArchiveConfig::digest_algorithms`](crate::ArchiveConfig::digest_algorithms).
+    pub fn content_ids(&self, hash: &Hash) -> Result<Vec<ContentId>, LookupError> {
+        let algorithms = self.digest_algorithms();
+        let mut ids = vec![ContentId::from(*hash)];
+        if !algorithms.is_empty() {
+            let path = self.get_path_of_stored_file(hash);
+            let digests = File::open(&path)
+                .and_then(|file| digest_reader(file, &algorithms))
+                .map_err(|err| LookupError::Read { path, source: err })?;
+            ids.extend(digests);
+        }
+        Ok(ids)
+    }
+
+    /// Returns a hasher that computes the hash of a file along with its digests with every algorithm in
+    /// [`ArchiveConfig::digest_algorithms`](crate::ArchiveConfig::digest_algorithms).
+    pub(crate) fn content_hasher(&self) -> ContentHasher {
+        ContentHasher {
+            hasher: blake3::Hasher::new(),
+            digests: self
+                .digest_algorithms()
+                .into_iter()
+                .map(|algorithm| (algorithm, Hasher::new(algorithm)))
+                .collect(),
+        }
+    }
+
+    /// Records the digests of a stored file, so that it can be found with [`MediaArchive::lookup`].
+    ///
+    /// Failing to record the digests doesn't fail storing the file, so errors are only logged.
+    pub(crate) fn record_digests(&self, hash: &Hash, ids: &[ContentId]) {
+        for id in ids {
+            let index_path = self.digest_index_path(id.algorithm, &id.digest);
+            let result = fs::create_dir_all(index_path.parent().expect("index path should have a parent"))
+                .and_then(|()| self.write_file_atomically(&index_path, hash.to_hex().as_bytes()));
+            if let Err(err) = result {
+                warn!("failed to record digest '{}': {}", id, err);
+            }
+        }
+    }
+
+    /// Computes and records the digests of a stored file whose digests weren't computed while storing it.
+    pub(crate) fn record_digests_of_stored_file(&self, hash: &Hash) {
+        let algorithms = self.digest_algorithms();
+        if algorithms.is_empty() {
+            return;
+        }
+
+        let path = self.get_path_of_stored_file(hash);
+        match File::open(&path).and_then(|file| digest_reader(file, &algorithms)) {
+            Ok(ids) => self.record_digests(hash, &ids),
+            Err(err) => warn!("failed to compute digests of file '{}': {}", path.display(), err),
+        }
+    }
+
+    /// Returns the configured algorithms whose digests are recorded for every stored file, in addition to its hash.
+    fn digest_algorithms(&self) -> Vec<HashAlgorithm> {
+        self.config.digest_algorithms.clone()
+    }
+
+    /// Returns the path of the file recording which stored file has the given digest.
+    fn digest_index_path(&self, algorithm: HashAlgorithm, digest: &[u8]) -> PathBuf {
+        let hex = to_hex(digest);
+        let mut path = self.archive_path.join(DIGESTS_DIRECTORY);
+        path.push(algorithm.name());
+        path.push(&hex[..2]);
+        path.push(hex);
+        path
+    }
+}
+
+/// A hash algorithm that can identify file contents.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// BLAKE3, which the archive identifies stored files with.
+    Blake3,
+    /// SHA-256, commonly used to publish checksums of files.
+    Sha256,
+    /// SHA-512, also commonly used to publish checksums of files.
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// Returns the multicodec code of the algorithm, used in the binary form of [`ContentId`].
+    #[must_use]
+    pub fn code(self) -> u8 {
+        match self {
+            HashAlgorithm::Blake3 => 0x1e,
+            HashAlgorithm::Sha256 => 0x12,
+            HashAlgorithm::Sha512 => 0x13,
+        }
+    }
+
+    /// Returns the algorithm with the given multicodec code, if it's supported.
+    #[must_use]
+    pub fn from_code(code: u8) -> Option<Self> {
+        [HashAlgorithm::Blake3, HashAlgorithm::Sha256, HashAlgorithm::Sha512]
+            .into_iter()
+            .find(|algorithm| algorithm.code() == code)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Returns the length of the digests computed by the algorithm, in bytes.
+    #[must_use]
+    pub fn digest_len(self) -> usize {
+        usize::from(self.encoded_digest_len())
+    }
+
+    /// Returns the length of the digests computed by the algorithm, as encoded in the binary form of [`ContentId`].
+    fn encoded_digest_len(self) -> u8 {
+        match self {
+            HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+/// A self-describing identifier of file contents, made of the hash algorithm and the digest it computed.
+///
+/// Its textual form is the hexadecimal encoding of a [multihash](https://multiformats.io/multihash/):
+/// the algorithm's multicodec code, the length of the digest, and the digest itself.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ContentId {
+    algorithm: HashAlgorithm,
+    digest: Vec<u8>,
+}
+
+impl ContentId {
+    /// Creates a content identifier from a digest computed with `algorithm`.
+    ///
+    /// Fails with [`ParseContentIdError::InvalidLength`] if the digest isn't [`HashAlgorithm::digest_len`] bytes long.
+    pub fn new(algorithm: HashAlgorithm, digest: Vec<u8>) -> Result<Self, ParseContentIdError> {
+        if digest.len() != algorithm.digest_len() {
+            return Err(ParseContentIdError::InvalidLength);
+        }
+        Ok(ContentId { algorithm, digest })
+    }
+
+    /// Returns the algorithm the digest was computed with.
+    #[must_use]
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the digest of the contents.
+    #[must_use]
+    pub fn digest(&self) -> &[u8] {
+        &self.digest
+    }
+
+    /// Computes the content identifier of the data in `reader` with the given algorithm.
+    pub fn from_reader(algorithm: HashAlgorithm, reader: impl Read) -> io::Result<Self> {
+        Ok(digest_reader(reader, &[algorithm])?.remove(0))
+    }
+
+    /// Returns the binary form of the identifier.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // The length of the digest is checked when the identifier is created.
+        let mut bytes = vec![self.algorithm.code(), self.algorithm.encoded_digest_len()];
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+}
+
+impl From<Hash> for ContentId {
+    fn from(hash: Hash) -> Self {
+        ContentId {
+            algorithm: HashAlgorithm::Blake3,
+            digest: hash.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl Display for ContentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&to_hex(&self.to_bytes()))
+    }
+}
+
+impl FromStr for ContentId {
+    type Err = ParseContentIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = from_hex(s).ok_or(ParseContentIdError::InvalidHex)?;
+        let [code, len, digest @ ..] = bytes.as_slice() else {
+            return Err(ParseContentIdError::InvalidLength);
+        };
+        let algorithm = HashAlgorithm::from_code(*code).ok_or(ParseContentIdError::UnsupportedAlgorithm(*code))?;
+        if usize::from(*len) != digest.len() {
+            return Err(ParseContentIdError::InvalidLength);
+        }
+        ContentId::new(algorithm, digest.to_vec())
+    }
+}
+
+impl Serialize for ContentId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Computes the hash of data along with its digests with other algorithms, so that it only has to be read once.
+#[derive(Debug)]
+pub(crate) struct ContentHasher {
+    hasher: blake3::Hasher,
+    digests: Vec<(HashAlgorithm, Hasher)>,
+}
+
+impl ContentHasher {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        for (_, hasher) in &mut self.digests {
+            hasher.update(data);
+        }
+    }
+
+    pub(crate) fn update_reader(&mut self, mut reader: impl Read) -> io::Result<()> {
+        let mut buffer = vec![0; COPY_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(len) => self.update(&buffer[..len]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Returns the hash of the data, and its content identifiers with the other algorithms.
+    pub(crate) fn finalize(self) -> (Hash, Vec<ContentId>) {
+        let ids = self
+            .digests
+            .into_iter()
+            .map(|(algorithm, hasher)| ContentId {
+                algorithm,
+                digest: hasher.finalize(),
+            })
+            .collect();
+        (self.hasher.finalize(), ids)
+    }
+}
+
+#[derive(Debug)]
+enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Computes the content identifiers of the data in `reader` with every given algorithm, reading it only once.
+fn digest_reader(mut reader: impl Read, algorithms: &[HashAlgorithm]) -> io::Result<Vec<ContentId>> {
+    let mut hashers: Vec<_> = algorithms.iter().map(|&algorithm| Hasher::new(algorithm)).collect();
+
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    loop {
+        let len = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        for hasher in &mut hashers {
+            hasher.update(&buffer[..len]);
+        }
+    }
+
+    Ok(algorithms
+        .iter()
+        .zip(hashers)
+        .map(|(&algorithm, hasher)| ContentId {
+            algorithm,
+            digest: hasher.finalize(),
+        })
+        .collect())
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            write!(hex, "{byte:02x}").expect("writing to a string should not fail");
+            hex
+        })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum LookupError {
+    #[error("digest index file '{0}' is corrupt")]
+    Corrupt(PathBuf),
+    #[error("failed to read '{path}': {source}")]
+    Read { path: PathBuf, source: io::Error },
+}
+
+#[derive(Debug, Error)]
+pub enum ParseContentIdError {
+    #[error("content identifier is not valid hexadecimal")]
+    InvalidHex,
+    #[error("content identifier has the wrong length")]
+    InvalidLength,
+    #[error("hash algorithm with multicodec code {0:#x} is not supported")]
+    UnsupportedAlgorithm(u8),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{temp_media_archive, TEST_DATA, TEST_DATA_HASH};
+    use crate::{ArchiveConfig, DiskStructure, StoreMethod};
+
+    const TEST_DATA_SHA256: &str = "916f0027a575074ce72a331777c3478d6513f786a591bd892da1a577bf2335f9";
+    const TEST_DATA_SHA512: &str = "0e1e21ecf105ec853d24d728867ad70613c21663a4693074b2a3619c1bd39d66\
+                                    b588c33723bb466c72424e80e3ca63c249078ab347bab9428500e7ee43059d0d";
+
+    #[test]
+    fn content_id_format() {
+        let id = ContentId::from(Hash::from_hex(TEST_DATA_HASH).unwrap());
+        assert_eq!(id.to_string(), format!("1e20{}", TEST_DATA_HASH));
+        assert_eq!(id.to_string().parse::<ContentId>().unwrap(), id);
+
+        let id = ContentId::from_reader(HashAlgorithm::Sha256, TEST_DATA.as_bytes()).unwrap();
+        assert_eq!(id.to_string(), format!("1220{}", TEST_DATA_SHA256));
+        assert_eq!(
+            serde_json::from_str::<ContentId>(&serde_json::to_string(&id).unwrap()).unwrap(),
+            id
+        );
+
+        let id = ContentId::from_reader(HashAlgorithm::Sha512, TEST_DATA.as_bytes()).unwrap();
+        assert_eq!(id.to_string(), format!("1340{}", TEST_DATA_SHA512));
+        assert_eq!(id.to_string().parse::<ContentId>().unwrap(), id);
+
+        assert!(matches!(
+            "1220abcd".parse::<ContentId>(),
+            Err(ParseContentIdError::InvalidLength)
+        ));
+        assert!(matches!(
+            format!("1320{}", TEST_DATA_SHA256).parse::<ContentId>(),
+            Err(ParseContentIdError::InvalidLength)
+        ));
+        assert!(matches!(
+            format!("1420{}", TEST_DATA_SHA256).parse::<ContentId>(),
+            Err(ParseContentIdError::UnsupportedAlgorithm(0x14))
+        ));
+        assert!(matches!(
+            "not hex".parse::<ContentId>(),
+            Err(ParseContentIdError::InvalidHex)
+        ));
+    }
+
+    #[test]
+    fn lookup_by_secondary_digest() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Bare);
+        let sha256_id: ContentId = format!("1220{}", TEST_DATA_SHA256).parse().unwrap();
+
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        assert_eq!(archive.lookup(&ContentId::from(hash)).unwrap(), Some(hash));
+        assert_eq!(archive.lookup(&sha256_id).unwrap(), None);
+
+        let config = ArchiveConfig {
+            digest_algorithms: vec![HashAlgorithm::Sha256],
+            ..archive.config().clone()
+        };
+        archive.set_config(config).unwrap();
+
+        // Storing an already stored file records its missing digests.
+        let file = temp_dir.join("file");
+        fs::write(&file, TEST_DATA).unwrap();
+        archive.store_file(&file, StoreMethod::Copy).unwrap();
+        assert_eq!(archive.lookup(&sha256_id).unwrap(), Some(hash));
+        assert_eq!(archive.content_ids(&hash).unwrap(), [ContentId::from(hash), sha256_id]);
+
+        let other_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+        let other_id = ContentId::from_reader(HashAlgorithm::Sha256, "other data".as_bytes()).unwrap();
+        assert_eq!(archive.lookup(&other_id).unwrap(), Some(other_hash));
+
+        let config = ArchiveConfig {
+            digest_algorithms: vec![HashAlgorithm::Sha256, HashAlgorithm::Sha512],
+            ..archive.config().clone()
+        };
+        archive.set_config(config).unwrap();
+
+        let file = temp_dir.join("moved file");
+        fs::write(&file, "moved data").unwrap();
+        let moved_hash = archive.store_file(&file, StoreMethod::Move).unwrap().hash();
+        let moved_id = ContentId::from_reader(HashAlgorithm::Sha512, "moved data".as_bytes()).unwrap();
+        assert_eq!(archive.lookup(&moved_id).unwrap(), Some(moved_hash));
+
+        assert_eq!(archive.lookup(&ContentId::from(crate::tests::ZERO_HASH)).unwrap(), None);
+
+        // Identifiers with a digest of the wrong length can't be created, but are never found anyway.
+        assert!(matches!(
+            ContentId::new(HashAlgorithm::Sha256, Vec::new()),
+            Err(ParseContentIdError::InvalidLength)
+        ));
+        let empty_id = ContentId {
+            algorithm: HashAlgorithm::Sha256,
+            digest: Vec::new(),
+        };
+        assert_eq!(archive.lookup(&empty_id).unwrap(), None);
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use relative_path::{RelativePath, RelativePathBuf};
+use tracing::{info, warn};
+
+use crate::deployment::{DeploymentRecord, DeploymentState};
+use crate::{hash_file, DeployError, DeployMethod, Hash, LockMode, Manifest, ManifestEntry, MediaArchive};
+
+impl MediaArchive {
+    /// Deploys every file in a manifest to the deployment directory.
+    ///
+    /// `policy` decides what happens to files that already exist in the deployment directory.
+    ///
+    /// Failing to deploy a file doesn't abort the operation. Instead, the result of deploying
+    /// each file is collected in the returned [`DeployManifestReport`].
+    /// The method each file was deployed with is recorded, see [`MediaArchive::deployments`].
+    #[tracing::instrument(skip(self, manifest), err)]
+    pub fn deploy_manifest(
+        &self,
+        manifest: &Manifest,
+        method: DeployMethod,
+        policy: ExistingFilePolicy,
+    ) -> Result<DeployManifestReport, DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        if self.deploy_path.is_none() {
+            return Err(DeployError::IsBareArchive);
+        }
+
+        let journal = self.begin_deploy(manifest.iter().map(|(path, entry)| (path, entry.hash, method)))?;
+        let mut state = self.load_deployment_state()?;
+        let mut report = DeployManifestReport::default();
+        for (path, entry) in manifest.iter() {
+            let result = self.deploy_manifest_entry(&mut state, path, entry, method, policy);
+            if let Err(err) = &result {
+                warn!("failed to deploy '{}': {}", path, err);
+            }
+            report.results.insert(path.to_owned(), result);
+        }
+        self.save_deployment_state(&state)?;
+        journal.complete();
+
+        info!("deployed manifest, {} files failed to deploy", report.errors().count());
+        Ok(report)
+    }
+
+    fn deploy_manifest_entry(
+        &self,
+        state: &mut DeploymentState,
+        path: &RelativePath,
+        entry: &ManifestEntry,
+        method: DeployMethod,
+        policy: ExistingFilePolicy,
+    ) -> Result<DeployOutcome, DeployError> {
+        let target_path = self.deploy_target_path(path)?;
+
+        let existing_metadata = match target_path.symlink_metadata() {
+            Ok(metadata) => Some(metadata),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(DeployError::Metadata {
+                    path: target_path,
+                    source: err,
+                })
+            }
+        };
+
+        let (outcome, method) = match existing_metadata {
+            None => (
+                DeployOutcome::Deployed,
+                self.deploy_to(&entry.hash, &target_path, method)?,
+            ),
+            Some(_) if policy == ExistingFilePolicy::Skip => return Ok(DeployOutcome::Skipped),
+            Some(metadata) if policy == ExistingFilePolicy::Fail || metadata.is_dir() => {
+                return Err(DeployError::AlreadyExists(target_path));
+            }
+            Some(_)
+                if policy == ExistingFilePolicy::OverwriteIfDifferent
+                    && file_has_hash(&target_path, &entry.hash, Some(entry.size))? =>
+            {
+                // The existing file wasn't necessarily deployed by the archive, so it isn't recorded,
+                // to never remove a file the user placed there when undeploying or synchronizing.
+                return Ok(DeployOutcome::Unchanged);
+            }
+            Some(_) => (
+                DeployOutcome::Overwritten,
+                self.replace_deployed_file(&entry.hash, &target_path, method)?,
+            ),
+        };
+
+        if matches!(method, DeployMethod::Copy | DeployMethod::Reflink) {
+            set_mode(&target_path, entry.mode);
+        }
+        state
+            .files
+            .insert(path.to_owned(), DeploymentRecord::new(entry.hash, method));
+        Ok(outcome)
+    }
+
+    /// Replaces an existing file in the deployment directory with the stored file with the given hash.
+    ///
+    /// The stored file is deployed next to the existing file, and then renamed over it,
+    /// so that the existing file is left untouched if deploying fails. Returns the method used.
+    pub(crate) fn replace_deployed_file(
+        &self,
+        hash: &Hash,
+        target_path: &Path,
+        method: DeployMethod,
+    ) -> Result<DeployMethod, DeployError> {
+        let temp_path = replacement_temp_path(target_path);
+        match fs::remove_file(&temp_path) {
+            Ok(()) => warn!("removed leftover temporary file '{}'", temp_path.display()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => {
+                return Err(DeployError::Metadata {
+                    path: temp_path,
+                    source: err,
+                })
+            }
+        }
+
+        let method = self.deploy_to(hash, &temp_path, method)?;
+        fs::rename(&temp_path, target_path).map_err(|err| {
+            let _ = fs::remove_file(&temp_path);
+            DeployError::Deploy {
+                from: temp_path,
+                to: target_path.to_owned(),
+                source: err,
+            }
+        })?;
+        Ok(method)
+    }
+}
+
+/// Returns the path a file replacing `target_path` is deployed to, before being renamed over it.
+pub(crate) fn replacement_temp_path(target_path: &Path) -> PathBuf {
+    let mut file_name = target_path
+        .file_name()
+        .expect("target path should have a file name")
+        .to_owned();
+    file_name.push(".media-archive-tmp");
+    target_path.with_file_name(file_name)
+}
+
+/// Returns whether a file (or the file a symlink points to) has the contents with the given hash.
+///
+/// If the size of the contents is known, files with a different size aren't hashed.
+pub(crate) fn file_has_hash(path: &Path, hash: &Hash, size: Option<u64>) -> Result<bool, DeployError> {
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        // Broken symlink.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => {
+            return Err(DeployError::Metadata {
+                path: path.to_owned(),
+                source: err,
+            })
+        }
+    };
+    if !metadata.is_file() || size.is_some_and(|size| metadata.len() != size) {
+        return Ok(false);
+    }
+
+    let actual_hash = hash_file(path).map_err(|err| DeployError::Read {
+        path: path.to_owned(),
+        source: err,
+    })?;
+    Ok(actual_hash == *hash)
+}
+
+/// Sets the permissions of a deployed file from the mode in its manifest entry.
+pub(crate) fn set_mode(path: &Path, mode: u32) {
+    #[cfg(target_family = "unix")]
+    let permissions = {
+        use std::os::unix::fs::PermissionsExt;
+        fs::Permissions::from_mode(mode & 0o7777)
+    };
+    #[cfg(not(target_family = "unix"))]
+    let permissions = {
+        let mut permissions = match path.metadata() {
+            Ok(metadata) => metadata.permissions(),
+            Err(err) => {
+                warn!("failed to get metadata of file '{}': {}", path.display(), err);
+                return;
+            }
+        };
+        permissions.set_readonly(mode & 0o222 == 0);
+        permissions
+    };
+
+    if let Err(err) = fs::set_permissions(path, permissions) {
+        warn!("failed to set permissions of file '{}': {}", path.display(), err);
+    }
+}
+
+/// What to do with files that already exist where a file is being deployed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExistingFilePolicy {
+    /// The existing file is left untouched.
+    Skip,
+    /// The existing file is replaced.
+    Overwrite,
+    /// The existing file is left untouched, and deploying the file fails.
+    Fail,
+    /// The existing file is replaced, unless it already has the contents of the file being deployed.
+    OverwriteIfDifferent,
+}
+
+/// The outcome of deploying a single file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeployOutcome {
+    /// The file was deployed.
+    Deployed,
+    /// The file was deployed, replacing an existing file.
+    Overwritten,
+    /// A file already existed, and was left untouched.
+    Skipped,
+    /// A file with the same contents already existed, and was left untouched.
+    ///
+    /// The file isn't recorded as deployed by the archive.
+    Unchanged,
+}
+
+/// The result of deploying a manifest with [`MediaArchive::deploy_manifest`].
+#[derive(Debug, Default)]
+pub struct DeployManifestReport {
+    /// The result of deploying each file, indexed by its path relative to the deployment directory.
+    pub results: BTreeMap<RelativePathBuf, Result<DeployOutcome, DeployError>>,
+}
+
+impl DeployManifestReport {
+    /// Returns an iterator over the files that failed to deploy.
+    pub fn errors(&self) -> impl Iterator<Item = (&RelativePath, &DeployError)> {
+        self.results
+            .iter()
+            .filter_map(|(path, result)| result.as_ref().err().map(|err| (path.as_relative_path(), err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::DiskStructure;
+
+    fn test_manifest(archive: &MediaArchive) -> Manifest {
+        let test_data_hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let other_data_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+        archive
+            .manifest_from_files([
+                (RelativePathBuf::from("a/b.txt"), test_data_hash),
+                (RelativePathBuf::from("c.txt"), other_data_hash),
+            ])
+            .unwrap()
+    }
+
+    #[test]
+    fn deploy_manifest() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let manifest = test_manifest(&archive);
+
+        let report = archive
+            .deploy_manifest(&manifest, DeployMethod::Copy, ExistingFilePolicy::Fail)
+            .unwrap();
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(report.results.len(), 2);
+        assert!(report
+            .results
+            .values()
+            .all(|result| matches!(result, Ok(DeployOutcome::Deployed))));
+
+        temp_dir.child("a/b.txt").assert(TEST_DATA);
+        temp_dir.child("c.txt").assert("other data");
+
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = temp_dir.child("c.txt").metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, ManifestEntry::DEFAULT_MODE);
+        }
+    }
+
+    #[test]
+    fn deploy_manifest_bare_archive() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let manifest = test_manifest(&archive);
+
+        assert!(matches!(
+            archive.deploy_manifest(&manifest, DeployMethod::Copy, ExistingFilePolicy::Fail),
+            Err(DeployError::IsBareArchive)
+        ));
+    }
+
+    fn deploy_manifest_with_existing_files(policy: ExistingFilePolicy) -> (assert_fs::TempDir, DeployManifestReport) {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let manifest = test_manifest(&archive);
+        temp_dir.child("a/b.txt").write_str(TEST_DATA).unwrap();
+        temp_dir.child("c.txt").write_str("user data").unwrap();
+
+        let report = archive
+            .deploy_manifest(&manifest, DeployMethod::Hardlink, policy)
+            .unwrap();
+        (temp_dir, report)
+    }
+
+    #[test]
+    fn deploy_manifest_skip() {
+        let (temp_dir, report) = deploy_manifest_with_existing_files(ExistingFilePolicy::Skip);
+        assert!(report
+            .results
+            .values()
+            .all(|result| matches!(result, Ok(DeployOutcome::Skipped))));
+        temp_dir.child("c.txt").assert("user data");
+    }
+
+    #[test]
+    fn deploy_manifest_fail() {
+        let (temp_dir, report) = deploy_manifest_with_existing_files(ExistingFilePolicy::Fail);
+        assert_eq!(report.errors().count(), 2);
+        assert!(matches!(
+            report.results[RelativePath::new("c.txt")],
+            Err(DeployError::AlreadyExists(_))
+        ));
+        temp_dir.child("c.txt").assert("user data");
+    }
+
+    #[test]
+    fn deploy_manifest_overwrite() {
+        let (temp_dir, report) = deploy_manifest_with_existing_files(ExistingFilePolicy::Overwrite);
+        assert!(report
+            .results
+            .values()
+            .all(|result| matches!(result, Ok(DeployOutcome::Overwritten))));
+        temp_dir.child("a/b.txt").assert(TEST_DATA);
+        temp_dir.child("c.txt").assert("other data");
+        temp_dir
+            .child("c.txt.media-archive-tmp")
+            .assert(predicates::path::missing());
+    }
+
+    #[test]
+    fn deploy_manifest_inside_archive() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let manifest = archive
+            .manifest_from_files([(RelativePathBuf::from(".media-archive/config.json"), hash)])
+            .unwrap();
+
+        let report = archive
+            .deploy_manifest(&manifest, DeployMethod::Copy, ExistingFilePolicy::Overwrite)
+            .unwrap();
+        assert!(matches!(
+            report.results[RelativePath::new(".media-archive/config.json")],
+            Err(DeployError::InsideArchive(_))
+        ));
+        MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+    }
+
+    #[test]
+    fn deploy_manifest_overwrite_if_different() {
+        let (temp_dir, report) = deploy_manifest_with_existing_files(ExistingFilePolicy::OverwriteIfDifferent);
+        assert!(matches!(
+            report.results[RelativePath::new("a/b.txt")],
+            Ok(DeployOutcome::Unchanged)
+        ));
+        assert!(matches!(
+            report.results[RelativePath::new("c.txt")],
+            Ok(DeployOutcome::Overwritten)
+        ));
+        temp_dir.child("c.txt").assert("other data");
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+        assert!(archive.deployment(RelativePath::new("a/b.txt")).unwrap().is_none());
+        archive.undeploy_all().unwrap();
+        temp_dir.child("a/b.txt").assert(TEST_DATA);
+    }
+}
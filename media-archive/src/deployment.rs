@@ -0,0 +1,400 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Tracking of the files the archive has deployed.
+//!
+//! The deployed files are recorded in a JSON file inside the archive directory, which is
+//! replaced atomically whenever it changes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::deploy::file_has_hash;
+use crate::manifest::hash_hex;
+use crate::targets::TARGETS_DIRECTORY;
+use crate::{DeployError, DeployMethod, Hash, LockMode, MediaArchive};
+
+const DEPLOYMENTS_FILE: &str = "deployments.json";
+const DEPLOYMENTS_VERSION: u32 = 1;
+
+/// The files deployed by the archive, indexed by their path relative to the deployment directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct DeploymentState {
+    version: u32,
+    pub(crate) files: BTreeMap<RelativePathBuf, DeploymentRecord>,
+}
+
+/// The record of a file deployed by the archive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeploymentRecord {
+    /// The hash of the deployed file.
+    #[serde(with = "hash_hex")]
+    pub hash: Hash,
+    /// How the file was deployed.
+    pub method: DeployMethod,
+    /// When the file was deployed.
+    pub deployed_at: SystemTime,
+    /// The size and modification time the file had the last time its contents were found to be unmodified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) stamp: Option<FileStamp>,
+}
+
+impl DeploymentRecord {
+    /// Creates the record of a file that was just deployed.
+    pub(crate) fn new(hash: Hash, method: DeployMethod) -> Self {
+        Self {
+            hash,
+            method,
+            deployed_at: SystemTime::now(),
+            stamp: None,
+        }
+    }
+}
+
+/// The size and modification time of a file, used to detect changes to it without hashing it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FileStamp {
+    pub(crate) len: u64,
+    pub(crate) modified: SystemTime,
+}
+
+impl FileStamp {
+    pub(crate) fn from_metadata(metadata: &fs::Metadata) -> Option<Self> {
+        Some(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+impl MediaArchive {
+    /// Returns the records of the files deployed by the archive, indexed by their path
+    /// relative to the deployment directory.
+    pub fn deployments(&self) -> Result<BTreeMap<RelativePathBuf, DeploymentRecord>, DeployError> {
+        Ok(self.load_deployment_state()?.files)
+    }
+
+    /// Returns the record of the file deployed at `path`, if it was deployed by the archive.
+    pub fn deployment(&self, path: &RelativePath) -> Result<Option<DeploymentRecord>, DeployError> {
+        Ok(self.load_deployment_state()?.files.remove(&path.normalize()))
+    }
+
+    /// Removes a file deployed by the archive from the deployment directory.
+    ///
+    /// Only files deployed by the archive can be removed, and only if they weren't modified
+    /// since being deployed, so user edits are never lost. Parent directories left empty are removed too.
+    ///
+    /// If the file had already been removed, it's simply forgotten.
+    #[tracing::instrument(skip(self), err)]
+    pub fn undeploy(&self, path: &RelativePath) -> Result<(), DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        let mut state = self.load_deployment_state()?;
+        let path = path.normalize();
+        let deployed = *state
+            .files
+            .get(&path)
+            .ok_or_else(|| DeployError::NotDeployed(path.clone()))?;
+
+        self.remove_deployed_file(&path, &deployed)?;
+        state.files.remove(&path);
+        self.save_deployment_state(&state)?;
+
+        info!("undeployed file successfully");
+        Ok(())
+    }
+
+    /// Removes every file deployed by the archive from the deployment directory.
+    ///
+    /// Like [`MediaArchive::undeploy`], files that were modified since being deployed are left untouched.
+    /// Failing to remove a file doesn't abort the operation. Instead, the result of removing
+    /// each file is collected in the returned [`UndeployReport`].
+    #[tracing::instrument(skip(self), err)]
+    pub fn undeploy_all(&self) -> Result<UndeployReport, DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        let mut state = self.load_deployment_state()?;
+
+        let mut report = UndeployReport::default();
+        for (path, deployed) in &state.files {
+            let result = self.remove_deployed_file(path, deployed).map(|_| ());
+            if let Err(err) = &result {
+                warn!("failed to undeploy '{}': {}", path, err);
+            }
+            report.results.insert(path.clone(), result);
+        }
+
+        state.files.retain(|path, _| report.results[path].is_err());
+        self.save_deployment_state(&state)?;
+
+        info!(
+            "undeployed files, {} files failed to be removed",
+            report.errors().count()
+        );
+        Ok(report)
+    }
+
+    /// Returns the path of the record of deployed files of the archive's deployment target.
+    pub(crate) fn deployment_state_path(&self) -> PathBuf {
+        match &self.target {
+            Some(name) => self.archive_path.join(TARGETS_DIRECTORY).join(format!("{}.json", name)),
+            None => self.archive_path.join(DEPLOYMENTS_FILE),
+        }
+    }
+
+    pub(crate) fn load_deployment_state(&self) -> Result<DeploymentState, DeployError> {
+        let file = match fs::File::open(self.deployment_state_path()) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(DeploymentState::default()),
+            Err(err) => return Err(DeployError::State(err)),
+        };
+
+        let state: DeploymentState =
+            serde_json::from_reader(BufReader::new(file)).map_err(|err| DeployError::State(err.into()))?;
+        if state.version != DEPLOYMENTS_VERSION {
+            return Err(DeployError::State(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported deployment state version {}", state.version),
+            )));
+        }
+        Ok(state)
+    }
+
+    pub(crate) fn save_deployment_state(&self, state: &DeploymentState) -> Result<(), DeployError> {
+        let contents = serde_json::to_vec(&DeploymentState {
+            version: DEPLOYMENTS_VERSION,
+            files: state.files.clone(),
+        })
+        .expect("deployment state serialization should not fail");
+        let path = self.deployment_state_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(DeployError::State)?;
+        }
+        self.write_file_atomically(&path, &contents).map_err(DeployError::State)
+    }
+
+    /// Records a file as deployed by the archive.
+    pub(crate) fn record_deployed_file(
+        &self,
+        path: &RelativePath,
+        hash: Hash,
+        method: DeployMethod,
+    ) -> Result<(), DeployError> {
+        let mut state = self.load_deployment_state()?;
+        state
+            .files
+            .insert(path.normalize(), DeploymentRecord::new(hash, method));
+        self.save_deployment_state(&state)
+    }
+
+    /// Removes a file deployed by the archive, unless it was modified after being deployed.
+    ///
+    /// Returns `false` if the file doesn't exist anymore. Parent directories left empty are removed too.
+    pub(crate) fn remove_deployed_file(
+        &self,
+        path: &RelativePath,
+        deployed: &DeploymentRecord,
+    ) -> Result<bool, DeployError> {
+        let target_path = self.deploy_target_path(path)?;
+        match target_path.symlink_metadata() {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => {
+                return Err(DeployError::Metadata {
+                    path: target_path,
+                    source: err,
+                })
+            }
+        }
+
+        if !self.deployed_file_is_unmodified(&target_path, deployed)? {
+            return Err(DeployError::Modified(target_path));
+        }
+        fs::remove_file(&target_path).map_err(|err| DeployError::Remove {
+            path: target_path.clone(),
+            source: err,
+        })?;
+
+        let deploy_path = self.deploy_path.as_ref().expect("deploy path was checked to exist");
+        remove_empty_parent_directories(&target_path, deploy_path);
+        Ok(true)
+    }
+
+    /// Returns whether a deployed file still has the contents it was deployed with.
+    ///
+    /// Symlinks that still point where they were made to point are considered unmodified,
+    /// even if they can't be followed, like rooted symlinks can't always be.
+    pub(crate) fn deployed_file_is_unmodified(
+        &self,
+        target_path: &Path,
+        record: &DeploymentRecord,
+    ) -> Result<bool, DeployError> {
+        if record.method == DeployMethod::Symlink && self.is_deployed_symlink(target_path, record) {
+            return Ok(true);
+        }
+        file_has_hash(target_path, &record.hash, None)
+    }
+
+    /// Returns whether `target_path` is a symlink that points where the archive would make it point
+    /// to deploy the file in `record`.
+    pub(crate) fn is_deployed_symlink(&self, target_path: &Path, record: &DeploymentRecord) -> bool {
+        let Ok(link_target) = fs::read_link(target_path) else {
+            return false;
+        };
+        let parent = target_path.parent().expect("target path should have a parent");
+        self.symlink_target(&self.get_path_of_stored_file(&record.hash), parent)
+            .is_ok_and(|expected_link_target| expected_link_target == link_target)
+    }
+
+    /// Returns the paths of every file in the deployment directory, except for directories
+    /// and the archive directory.
+    pub(crate) fn scan_deploy_directory(&self) -> Result<Vec<RelativePathBuf>, DeployError> {
+        let deploy_path = self.deploy_path.as_ref().ok_or(DeployError::IsBareArchive)?;
+
+        let mut files = Vec::new();
+        let mut pending_directories = vec![deploy_path.clone()];
+        while let Some(directory) = pending_directories.pop() {
+            let entries = fs::read_dir(&directory).map_err(|err| DeployError::Read {
+                path: directory.clone(),
+                source: err,
+            })?;
+
+            for entry in entries {
+                let entry = entry.map_err(|err| DeployError::Read {
+                    path: directory.clone(),
+                    source: err,
+                })?;
+                let path = entry.path();
+                let file_type = entry.file_type().map_err(|err| DeployError::Metadata {
+                    path: path.clone(),
+                    source: err,
+                })?;
+
+                if file_type.is_dir() {
+                    if path != self.archive_path {
+                        pending_directories.push(path);
+                    }
+                    continue;
+                }
+
+                let relative_path = path
+                    .strip_prefix(deploy_path)
+                    .ok()
+                    .and_then(|relative_path| RelativePathBuf::from_path(relative_path).ok());
+                if let Some(relative_path) = relative_path {
+                    files.push(relative_path);
+                } else {
+                    warn!("ignoring file with invalid path '{}'", path.display());
+                }
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// The result of removing every deployed file with [`MediaArchive::undeploy_all`].
+#[derive(Debug, Default)]
+pub struct UndeployReport {
+    /// The result of removing each file, indexed by its path relative to the deployment directory.
+    pub results: BTreeMap<RelativePathBuf, Result<(), DeployError>>,
+}
+
+impl UndeployReport {
+    /// Returns an iterator over the files that failed to be removed.
+    pub fn errors(&self) -> impl Iterator<Item = (&RelativePath, &DeployError)> {
+        self.results
+            .iter()
+            .filter_map(|(path, result)| result.as_ref().err().map(|err| (path.as_relative_path(), err)))
+    }
+}
+
+/// Removes the empty directories between `path` and `root`, starting from `path`'s parent.
+pub(crate) fn remove_empty_parent_directories(path: &Path, root: &Path) {
+    for directory in path.ancestors().skip(1) {
+        if directory == root || !directory.starts_with(root) || fs::remove_dir(directory).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+    use predicates::prelude::*;
+
+    use crate::deploy::set_mode;
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::DiskStructure;
+
+    #[test]
+    fn undeploy() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        archive
+            .deploy_file(&hash, RelativePath::new("a/b/c.txt"), DeployMethod::Symlink)
+            .unwrap();
+        temp_dir.child("a/user.txt").write_str(TEST_DATA).unwrap();
+
+        archive.undeploy(RelativePath::new("a/b/c.txt")).unwrap();
+        temp_dir.child("a/b").assert(predicate::path::missing());
+        temp_dir.child("a/user.txt").assert(TEST_DATA);
+        assert!(archive.contains(&hash));
+
+        assert!(matches!(
+            archive.undeploy(RelativePath::new("a/b/c.txt")),
+            Err(DeployError::NotDeployed(_))
+        ));
+        assert!(matches!(
+            archive.undeploy(RelativePath::new("a/user.txt")),
+            Err(DeployError::NotDeployed(_))
+        ));
+    }
+
+    #[test]
+    fn undeploy_all() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        for path in ["a.txt", "b/c.txt", "d.txt"] {
+            archive
+                .deploy_file(&hash, RelativePath::new(path), DeployMethod::Copy)
+                .unwrap();
+        }
+        set_mode(temp_dir.child("a.txt").path(), 0o644);
+        temp_dir.child("a.txt").write_str("modified data").unwrap();
+        fs::remove_file(temp_dir.child("d.txt")).unwrap();
+
+        let report = archive.undeploy_all().unwrap();
+        assert_eq!(report.results.len(), 3);
+        assert!(matches!(
+            report.results[RelativePath::new("a.txt")],
+            Err(DeployError::Modified(_))
+        ));
+        assert_eq!(report.errors().count(), 1);
+        temp_dir.child("a.txt").assert("modified data");
+        temp_dir.child("b").assert(predicate::path::missing());
+
+        let report = archive.undeploy_all().unwrap();
+        assert_eq!(report.results.len(), 1);
+    }
+}
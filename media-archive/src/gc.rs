@@ -0,0 +1,365 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    read_hash_directory, BlobError, DeployError, Hash, ListBlobsError, LockError, LockMode, ManifestError, MediaArchive,
+};
+
+const PINS_DIRECTORY: &str = "pins";
+
+impl MediaArchive {
+    /// Removes the stored file with the given hash from the archive.
+    #[tracing::instrument(skip(self), err)]
+    pub fn remove_blob(&self, hash: &Hash) -> Result<(), BlobError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(BlobError::Lock)?;
+        let (path, _metadata) = self.stored_file_metadata(hash)?;
+
+        // Windows doesn't allow removing read only files.
+        #[cfg(target_family = "windows")]
+        {
+            let mut permissions = _metadata.permissions();
+            permissions.set_readonly(false);
+            fs::set_permissions(&path, permissions).map_err(|err| BlobError::Remove {
+                path: path.clone(),
+                source: err,
+            })?;
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                info!("removed stored file successfully");
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Err(BlobError::NotFound(*hash)),
+            Err(err) => Err(BlobError::Remove { path, source: err }),
+        }
+    }
+
+    /// Pins the stored file with the given hash, protecting it from garbage collection.
+    #[tracing::instrument(skip(self), err)]
+    pub fn pin(&self, hash: &Hash) -> Result<(), PinError> {
+        let _lock = self.lock(LockMode::Shared).map_err(PinError::Lock)?;
+        if !self.contains(hash) {
+            return Err(PinError::NotFound(*hash));
+        }
+
+        let pins_path = self.archive_path.join(PINS_DIRECTORY);
+        fs::create_dir_all(&pins_path).map_err(PinError::CreateDir)?;
+        File::create(pins_path.join(hash.to_hex().as_str())).map_err(|err| PinError::Write {
+            hash: *hash,
+            source: err,
+        })?;
+
+        info!("pinned file successfully");
+        Ok(())
+    }
+
+    /// Unpins the stored file with the given hash.
+    ///
+    /// Unpinning a file that isn't pinned does nothing.
+    #[tracing::instrument(skip(self), err)]
+    pub fn unpin(&self, hash: &Hash) -> Result<(), PinError> {
+        let _lock = self.lock(LockMode::Shared).map_err(PinError::Lock)?;
+        let pin_path = self.archive_path.join(PINS_DIRECTORY).join(hash.to_hex().as_str());
+        match fs::remove_file(pin_path) {
+            Ok(()) => {
+                info!("unpinned file successfully");
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(PinError::Write {
+                hash: *hash,
+                source: err,
+            }),
+        }
+    }
+
+    /// Returns the hashes of the pinned files.
+    pub fn pins(&self) -> Result<Vec<Hash>, PinError> {
+        read_hash_directory(&self.archive_path.join(PINS_DIRECTORY)).map_err(PinError::Read)
+    }
+
+    /// Returns the hashes of the stored files that must not be garbage collected.
+    pub(crate) fn gc_roots(&self) -> Result<HashSet<Hash>, GcError> {
+        let mut roots: HashSet<Hash> = self.pins().map_err(GcError::Pins)?.into_iter().collect();
+
+        for manifest_hash in self.manifests().map_err(GcError::Manifests)? {
+            let manifest = self.load_manifest(&manifest_hash).map_err(GcError::Manifests)?;
+            roots.insert(manifest_hash);
+            roots.extend(manifest.iter().map(|(_, entry)| entry.hash));
+        }
+
+        let deployments = self.deployments().map_err(GcError::Deployments)?;
+        roots.extend(deployments.values().map(|record| record.hash));
+        for name in self.config.targets.keys() {
+            let target = self.target(name).expect("target should be in the configuration");
+            let deployments = target.deployments().map_err(GcError::Deployments)?;
+            roots.extend(deployments.values().map(|record| record.hash));
+        }
+
+        Ok(roots)
+    }
+
+    /// Removes stored files that aren't reachable from any root.
+    ///
+    /// Pinned files, saved manifests and deployed files (in any deployment target) are roots,
+    /// and so are the files referenced by saved manifests.
+    ///
+    /// Files modified within the grace period are never removed, to avoid removing files
+    /// that were just stored by an operation that hasn't yet had the chance to reference them.
+    /// Storing a file updates its modification time, even if it had already been stored.
+    #[tracing::instrument(skip(self), err)]
+    pub fn collect_garbage(&self, options: GcOptions) -> Result<GcReport, GcError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(GcError::Lock)?;
+        let roots = self.gc_roots()?;
+        let now = SystemTime::now();
+
+        let mut report = GcReport::default();
+        let mut garbage = Vec::new();
+        for result in self.blobs() {
+            let blob = match result {
+                Ok(blob) => blob,
+                Err(err) => {
+                    report.problems.push(GcProblem::List(err));
+                    continue;
+                }
+            };
+
+            if roots.contains(&blob.hash) {
+                continue;
+            }
+            if now.duration_since(blob.modified).unwrap_or_default() < options.grace_period {
+                report.kept_in_grace_period += 1;
+                continue;
+            }
+            garbage.push(blob);
+        }
+
+        let journal = if options.dry_run || garbage.is_empty() {
+            None
+        } else {
+            let hashes: Vec<Hash> = garbage.iter().map(|blob| blob.hash).collect();
+            Some(self.begin_gc(&hashes, options.grace_period)?)
+        };
+        for blob in garbage {
+            if !options.dry_run {
+                if let Err(err) = self.remove_blob(&blob.hash) {
+                    report.problems.push(GcProblem::Remove(err));
+                    continue;
+                }
+            }
+            report.removed.push(blob.hash);
+            report.freed_bytes += blob.len;
+        }
+        if let Some(journal) = journal {
+            journal.complete();
+        }
+
+        info!(
+            "removed {} stored files, freeing {} bytes",
+            report.removed.len(),
+            report.freed_bytes
+        );
+        Ok(report)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GcOptions {
+    /// Whether to only report which files would be removed, without removing them.
+    pub dry_run: bool,
+    /// How long after being stored files are protected from garbage collection.
+    pub grace_period: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            grace_period: Duration::from_hours(24),
+        }
+    }
+}
+
+/// The result of collecting garbage with [`MediaArchive::collect_garbage`].
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// The hashes of the removed files (or of the files that would be removed, in a dry run).
+    pub removed: Vec<Hash>,
+    /// The total size of the removed files, in bytes.
+    pub freed_bytes: u64,
+    /// The number of unreachable files that were kept because they are within the grace period.
+    pub kept_in_grace_period: usize,
+    /// The problems that occurred while collecting garbage.
+    pub problems: Vec<GcProblem>,
+}
+
+#[derive(Debug, Error)]
+pub enum GcProblem {
+    #[error(transparent)]
+    List(ListBlobsError),
+    #[error(transparent)]
+    Remove(BlobError),
+}
+
+#[derive(Debug, Error)]
+pub enum GcError {
+    #[error("failed to read the record of deployed files: {0}")]
+    Deployments(#[source] DeployError),
+    #[error("failed to record the operation in the journal: {0}")]
+    Journal(#[source] io::Error),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("failed to read manifests: {0}")]
+    Manifests(#[source] ManifestError),
+    #[error("failed to read pins: {0}")]
+    Pins(#[source] PinError),
+}
+
+#[derive(Debug, Error)]
+pub enum PinError {
+    #[error("failed to create pins directory: {0}")]
+    CreateDir(#[source] io::Error),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("file with hash '{0}' not found in the archive")]
+    NotFound(Hash),
+    #[error("failed to read pins: {0}")]
+    Read(#[source] io::Error),
+    #[error("failed to write pin of file with hash '{hash}': {source}")]
+    Write { hash: Hash, source: io::Error },
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use relative_path::{RelativePath, RelativePathBuf};
+
+    use crate::tests::{temp_media_archive, TEST_DATA, ZERO_HASH};
+    use crate::{DeployMethod, DiskStructure};
+
+    pub(crate) const NO_GRACE_PERIOD: GcOptions = GcOptions {
+        dry_run: false,
+        grace_period: Duration::ZERO,
+    };
+
+    #[test]
+    fn remove_blob() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        archive.remove_blob(&hash).expect("failed to remove stored file");
+        assert!(!archive.contains(&hash));
+        assert!(matches!(archive.remove_blob(&hash), Err(BlobError::NotFound(_))));
+    }
+
+    #[test]
+    fn pins() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        assert!(matches!(archive.pin(&ZERO_HASH), Err(PinError::NotFound(_))));
+        assert!(archive.pins().unwrap().is_empty());
+
+        archive.pin(&hash).unwrap();
+        assert_eq!(archive.pins().unwrap(), [hash]);
+
+        archive.unpin(&hash).unwrap();
+        archive.unpin(&hash).unwrap();
+        assert!(archive.pins().unwrap().is_empty());
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let pinned_hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let unpinned_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+        archive.pin(&pinned_hash).unwrap();
+
+        let report = archive
+            .collect_garbage(GcOptions {
+                dry_run: true,
+                ..NO_GRACE_PERIOD
+            })
+            .unwrap();
+        assert_eq!(report.removed, [unpinned_hash]);
+        assert_eq!(report.freed_bytes, "other data".len() as u64);
+        assert!(archive.contains(&unpinned_hash));
+
+        let report = archive.collect_garbage(NO_GRACE_PERIOD).unwrap();
+        assert_eq!(report.removed, [unpinned_hash]);
+        assert!(report.problems.is_empty());
+        assert!(archive.contains(&pinned_hash));
+        assert!(!archive.contains(&unpinned_hash));
+    }
+
+    #[test]
+    fn collect_garbage_keeps_manifests() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        let manifest = archive
+            .manifest_from_files([(RelativePathBuf::from("a.txt"), hash)])
+            .unwrap();
+        let manifest_hash = archive.save_manifest(&manifest).unwrap();
+
+        let report = archive.collect_garbage(NO_GRACE_PERIOD).unwrap();
+        assert!(report.removed.is_empty());
+        assert!(archive.contains(&hash));
+
+        archive.remove_manifest(&manifest_hash).unwrap();
+        let mut removed = archive.collect_garbage(NO_GRACE_PERIOD).unwrap().removed;
+        removed.sort_by_key(Hash::to_hex);
+        let mut expected = [hash, manifest_hash];
+        expected.sort_by_key(Hash::to_hex);
+        assert_eq!(removed, expected);
+    }
+
+    #[test]
+    fn collect_garbage_grace_period() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        let report = archive.collect_garbage(GcOptions::default()).unwrap();
+        assert!(report.removed.is_empty());
+        assert_eq!(report.kept_in_grace_period, 1);
+        assert!(archive.contains(&hash));
+    }
+
+    #[test]
+    fn collect_garbage_keeps_deployed_files() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        archive
+            .deploy_file(&hash, RelativePath::new("a.txt"), DeployMethod::Symlink)
+            .unwrap();
+
+        let report = archive.collect_garbage(NO_GRACE_PERIOD).unwrap();
+        assert!(report.removed.is_empty());
+
+        archive.undeploy(RelativePath::new("a.txt")).unwrap();
+        let report = archive.collect_garbage(NO_GRACE_PERIOD).unwrap();
+        assert_eq!(report.removed, [hash]);
+    }
+}
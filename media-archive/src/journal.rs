@@ -0,0 +1,725 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A write-ahead journal of the operations in progress, so that interrupted operations can be recovered.
+//!
+//! Before an operation that can't be done atomically starts changing anything, an entry describing it
+//! is written to the journal directory. The entry is removed once the operation is done. Entries are only
+//! left behind when the operation was interrupted, for example by a crash or a power loss, and they are
+//! used by [`MediaArchive::recover`] to finish or roll back what the operation left half done.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::deploy::replacement_temp_path;
+use crate::deployment::{DeploymentRecord, FileStamp};
+use crate::manifest::hash_hex;
+use crate::{
+    set_readonly, touch, BlobError, DeployError, DeployMethod, GcError, Hash, LockError, LockHolder, LockMode,
+    MediaArchive, TEMP_DIRECTORY, TEMP_FILE_PREFIX,
+};
+
+const JOURNAL_DIRECTORY: &str = "journal";
+const JOURNAL_VERSION: u32 = 1;
+const JOURNAL_ENTRY_EXTENSION: &str = "json";
+const INVALID_JOURNAL_ENTRY_EXTENSION: &str = "invalid";
+
+/// Distinguishes the journal entries written by the same process.
+static NEXT_ENTRY_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalFile {
+    version: u32,
+    #[serde(flatten)]
+    entry: JournalEntry,
+}
+
+/// An operation in progress, as recorded in the journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "lowercase")]
+pub(crate) enum JournalEntry {
+    /// A file being moved into the store.
+    Store {
+        source: PathBuf,
+        #[serde(with = "hash_hex")]
+        hash: Hash,
+    },
+    /// Files being deployed, which aren't recorded as deployed yet.
+    Deploy {
+        /// The deployment target the files are deployed to, or `None` for the main deployment directory.
+        target: Option<String>,
+        files: BTreeMap<RelativePathBuf, JournaledDeploy>,
+    },
+    /// Stored files being removed by the garbage collector.
+    Gc {
+        /// Files modified after this time were stored again after being found to be garbage.
+        cutoff: SystemTime,
+        hashes: Vec<JournaledHash>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JournaledDeploy {
+    #[serde(with = "hash_hex")]
+    hash: Hash,
+    method: DeployMethod,
+    /// Whether a file already existed where the file is deployed, in which case it's replaced atomically.
+    existed: bool,
+    /// The size and modification time of the file that already existed, to tell whether it was replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stamp: Option<FileStamp>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct JournaledHash(#[serde(with = "hash_hex")] Hash);
+
+/// The journal entry of an operation in progress.
+///
+/// If the operation fails, and doesn't call [`JournalRecord::complete`], what it left half done
+/// is recovered as soon as the record is dropped.
+#[must_use]
+#[derive(Debug)]
+pub(crate) struct JournalRecord<'a> {
+    archive: &'a MediaArchive,
+    path: PathBuf,
+    completed: bool,
+}
+
+impl JournalRecord<'_> {
+    /// Removes the entry from the journal, once the operation is done.
+    pub(crate) fn complete(mut self) {
+        self.completed = true;
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("failed to remove journal entry '{}': {}", self.path.display(), err);
+        }
+    }
+}
+
+impl Drop for JournalRecord<'_> {
+    fn drop(&mut self) {
+        // After a panic, the entry is left to be recovered the next time the archive is opened.
+        if self.completed || thread::panicking() {
+            return;
+        }
+        if let Err(err) = self.archive.recover_entry(&self.path, &mut RecoveryReport::default()) {
+            warn!("failed to recover failed operation, leaving it in the journal: {}", err);
+        }
+    }
+}
+
+impl MediaArchive {
+    /// Records an operation in the journal, before it starts.
+    ///
+    /// The entry is synced to disk, so that it survives a crash in the middle of the operation.
+    pub(crate) fn begin_operation(&self, entry: JournalEntry) -> io::Result<JournalRecord<'_>> {
+        let journal_path = self.archive_path.join(JOURNAL_DIRECTORY);
+        fs::create_dir_all(&journal_path)?;
+
+        // Entries are named after the time they were written first, so that sorting them by name
+        // sorts them in the order the operations started.
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let path = journal_path.join(format!(
+            "{:020}-{}-{}.{}",
+            nanos,
+            std::process::id(),
+            NEXT_ENTRY_ID.fetch_add(1, Ordering::Relaxed),
+            JOURNAL_ENTRY_EXTENSION
+        ));
+
+        let contents = serde_json::to_vec(&JournalFile {
+            version: JOURNAL_VERSION,
+            entry,
+        })
+        .expect("journal entry serialization should not fail");
+        self.write_file_atomically(&path, &contents)?;
+        Ok(JournalRecord {
+            archive: self,
+            path,
+            completed: false,
+        })
+    }
+
+    /// Records the deployment of files in the journal, before it starts.
+    pub(crate) fn begin_deploy<'a>(
+        &self,
+        files: impl IntoIterator<Item = (&'a RelativePath, Hash, DeployMethod)>,
+    ) -> Result<JournalRecord<'_>, DeployError> {
+        let files = files
+            .into_iter()
+            .filter_map(|(path, hash, method)| {
+                // Invalid paths fail to deploy anyway.
+                let target_path = self.deploy_target_path(path).ok()?;
+                let metadata = target_path.symlink_metadata();
+                let existed = !matches!(&metadata, Err(err) if err.kind() == io::ErrorKind::NotFound);
+                let stamp = metadata.ok().and_then(|metadata| FileStamp::from_metadata(&metadata));
+                Some((
+                    path.normalize(),
+                    JournaledDeploy {
+                        hash,
+                        method,
+                        existed,
+                        stamp,
+                    },
+                ))
+            })
+            .collect();
+
+        self.begin_operation(JournalEntry::Deploy {
+            target: self.target.clone(),
+            files,
+        })
+        .map_err(DeployError::Journal)
+    }
+
+    /// Records the removal of stored files by the garbage collector in the journal, before it starts.
+    pub(crate) fn begin_gc(&self, hashes: &[Hash], grace_period: Duration) -> Result<JournalRecord<'_>, GcError> {
+        let cutoff = SystemTime::now()
+            .checked_sub(grace_period)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        self.begin_operation(JournalEntry::Gc {
+            cutoff,
+            hashes: hashes.iter().copied().map(JournaledHash).collect(),
+        })
+        .map_err(GcError::Journal)
+    }
+
+    /// Finishes or rolls back the operations that were interrupted, such as by a crash or a power loss.
+    ///
+    /// Files moved into the store are finished being stored, deployed files that weren't recorded yet
+    /// are recorded (if they're complete) or removed (if they aren't), and stored files the garbage
+    /// collector was removing are removed, unless they were referenced or stored again since.
+    /// Temporary files left behind by processes that don't exist anymore are removed too.
+    ///
+    /// Failing to recover an operation doesn't abort recovery, the operation is left in the journal instead.
+    /// Journal entries that can't be read are set aside, and not recovered.
+    ///
+    /// This is done by [`MediaArchive::open`] if the archive isn't in use by someone else.
+    #[tracing::instrument(skip(self), err)]
+    pub fn recover(&self) -> Result<RecoveryReport, RecoveryError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(RecoveryError::Lock)?;
+        let mut report = RecoveryReport {
+            removed_temp_files: self.remove_temp_files()?,
+            ..RecoveryReport::default()
+        };
+
+        for path in self.journal_entries().map_err(RecoveryError::Read)? {
+            if let Err(err) = self.recover_entry(&path, &mut report) {
+                warn!("failed to recover journal entry '{}': {}", path.display(), err);
+                report.failed += 1;
+            }
+        }
+
+        info!(
+            "recovered interrupted operations, {} steps finished, {} rolled back, {} left pending, {} failed",
+            report.finished, report.rolled_back, report.pending, report.failed
+        );
+        Ok(report)
+    }
+
+    /// Recovers interrupted operations automatically, when the archive is opened.
+    ///
+    /// Recovery is skipped if there's nothing to recover, or if the archive is locked, since operations
+    /// in progress can't be told apart from interrupted ones. Failing to recover doesn't prevent opening the archive.
+    pub(crate) fn recover_on_open(&self) {
+        let has_journal_entries = self.journal_entries().is_ok_and(|entries| !entries.is_empty());
+        let has_stale_temp_files = fs::read_dir(self.archive_path.join(TEMP_DIRECTORY))
+            .is_ok_and(|mut entries| entries.any(|entry| entry.is_ok_and(|entry| is_stale_temp_file(&entry.path()))));
+        if !has_journal_entries && !has_stale_temp_files {
+            return;
+        }
+
+        let _lock = match self
+            .lock
+            .acquire(&self.archive_path, LockMode::Exclusive, Duration::ZERO)
+        {
+            Ok(lock) => lock,
+            Err(err) => {
+                debug!("not recovering interrupted operations, the archive is in use: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = self.recover() {
+            warn!("failed to recover interrupted operations: {}", err);
+        }
+    }
+
+    /// Returns the paths of the entries in the journal, in the order they were written.
+    fn journal_entries(&self) -> io::Result<Vec<PathBuf>> {
+        let mut entries = match fs::read_dir(self.archive_path.join(JOURNAL_DIRECTORY)) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .filter(|path| {
+                    path.as_ref().map_or(true, |path| {
+                        path.extension()
+                            .is_some_and(|extension| extension == JOURNAL_ENTRY_EXTENSION)
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// Finishes or rolls back the operation recorded in a journal entry, and removes the entry.
+    ///
+    /// Entries that can't be read are renamed, so that they're set aside, but not lost.
+    fn recover_entry(&self, path: &Path, report: &mut RecoveryReport) -> Result<(), RecoveryError> {
+        let entry = match read_journal_entry(path) {
+            Ok(entry) => entry,
+            Err(err @ (RecoveryError::Parse { .. } | RecoveryError::UnsupportedVersion { .. })) => {
+                let invalid_path = path.with_extension(INVALID_JOURNAL_ENTRY_EXTENSION);
+                fs::rename(path, &invalid_path).map_err(|err| RecoveryError::SetAside {
+                    path: path.to_owned(),
+                    source: err,
+                })?;
+                warn!(
+                    "set aside invalid journal entry as '{}': {}",
+                    invalid_path.display(),
+                    err
+                );
+                report.set_aside += 1;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        if self.recover_operation(entry, report)? {
+            fs::remove_file(path).map_err(|err| RecoveryError::Remove {
+                path: path.to_owned(),
+                source: err,
+            })?;
+        } else {
+            report.pending += 1;
+        }
+        Ok(())
+    }
+
+    /// Removes the temporary files left behind by processes that don't exist anymore, and returns how many
+    /// were removed.
+    fn remove_temp_files(&self) -> Result<usize, RecoveryError> {
+        let entries = match fs::read_dir(self.archive_path.join(TEMP_DIRECTORY)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(RecoveryError::Read(err)),
+        };
+
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry.map_err(RecoveryError::Read)?.path();
+            if !is_stale_temp_file(&path) {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(err) => warn!("failed to remove leftover temporary file '{}': {}", path.display(), err),
+            }
+        }
+        if removed > 0 {
+            warn!("removed {} leftover temporary files", removed);
+        }
+        Ok(removed)
+    }
+
+    /// Finishes or rolls back an interrupted operation.
+    ///
+    /// Returns `false` if the operation can't be recovered by this archive handle, and must be left in the journal.
+    fn recover_operation(&self, entry: JournalEntry, report: &mut RecoveryReport) -> Result<bool, RecoveryError> {
+        match entry {
+            JournalEntry::Store { source, hash } => {
+                let path = self.get_path_of_stored_file(&hash);
+                if path.exists() {
+                    touch(&path);
+                    set_readonly(&path);
+                    self.record_digests_of_stored_file(&hash);
+                    warn!("finished moving '{}' into the store", source.display());
+                    report.finished += 1;
+                }
+                Ok(true)
+            }
+            JournalEntry::Deploy { target, files } => {
+                let target_archive;
+                let archive = match target {
+                    Some(name) => {
+                        let Ok(archive) = self.target(&name) else {
+                            warn!(
+                                "discarding interrupted deployment to removed deployment target '{}'",
+                                name
+                            );
+                            return Ok(true);
+                        };
+                        target_archive = archive;
+                        &target_archive
+                    }
+                    None if self.target.is_none() && self.deploy_path.is_some() => self,
+                    None => return Ok(false),
+                };
+                archive.recover_deploy(files, report).map_err(RecoveryError::Deploy)?;
+                Ok(true)
+            }
+            JournalEntry::Gc { cutoff, hashes } => {
+                let roots = self.gc_roots().map_err(RecoveryError::Gc)?;
+                for JournaledHash(hash) in hashes {
+                    if roots.contains(&hash) {
+                        continue;
+                    }
+                    let modified = match self.stored_file_metadata(&hash) {
+                        Ok((_, metadata)) => metadata.modified().ok(),
+                        Err(BlobError::NotFound(_)) => continue,
+                        Err(err) => return Err(RecoveryError::Blob(err)),
+                    };
+                    if modified.is_some_and(|modified| modified <= cutoff) {
+                        self.remove_blob(&hash).map_err(RecoveryError::Blob)?;
+                        report.finished += 1;
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// Records the deployed files that were completely deployed, and removes the rest.
+    fn recover_deploy(
+        &self,
+        files: BTreeMap<RelativePathBuf, JournaledDeploy>,
+        report: &mut RecoveryReport,
+    ) -> Result<(), DeployError> {
+        let mut state = self.load_deployment_state()?;
+        for (path, file) in files {
+            let target_path = self.deploy_target_path(&path)?;
+            if remove_if_exists(&replacement_temp_path(&target_path))? {
+                report.rolled_back += 1;
+            }
+            // Files that were already deployed before the operation started are left as they are.
+            let recorded = state
+                .files
+                .get(&path)
+                .copied()
+                .filter(|record| record.hash == file.hash);
+            if recorded.is_some() && file.existed {
+                continue;
+            }
+
+            let metadata = match target_path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(DeployError::Metadata {
+                        path: target_path,
+                        source: err,
+                    })
+                }
+            };
+            // A file that existed before the operation started is only recorded if the operation replaced it,
+            // since the user may have placed a file with the same contents there.
+            if file.existed
+                && !file
+                    .stamp
+                    .zip(FileStamp::from_metadata(&metadata))
+                    .is_some_and(|(before, now)| before != now)
+            {
+                continue;
+            }
+            // The method chosen automatically isn't known, so such files are recorded as copies.
+            let record = recorded.unwrap_or_else(|| {
+                let method = match file.method {
+                    _ if metadata.is_symlink() => DeployMethod::Symlink,
+                    method @ (DeployMethod::Hardlink | DeployMethod::Reflink) => method,
+                    DeployMethod::Auto | DeployMethod::Copy | DeployMethod::Symlink => DeployMethod::Copy,
+                };
+                DeploymentRecord::new(file.hash, method)
+            });
+
+            if self.deployed_file_is_unmodified(&target_path, &record)? {
+                if recorded.is_none() {
+                    warn!("recording interrupted deployment of '{}'", path);
+                    state.files.insert(path, record);
+                    report.finished += 1;
+                }
+            } else if !file.existed && !metadata.is_dir() {
+                warn!("removing partially deployed file '{}'", path);
+                remove_if_exists(&target_path)?;
+                report.rolled_back += 1;
+            }
+        }
+        self.save_deployment_state(&state)
+    }
+}
+
+/// Reads and parses a journal entry.
+fn read_journal_entry(path: &Path) -> Result<JournalEntry, RecoveryError> {
+    let file = File::open(path).map_err(RecoveryError::Read)?;
+    let journal_file: JournalFile =
+        serde_json::from_reader(BufReader::new(file)).map_err(|err| RecoveryError::Parse {
+            path: path.to_owned(),
+            source: err,
+        })?;
+    if journal_file.version != JOURNAL_VERSION {
+        return Err(RecoveryError::UnsupportedVersion {
+            path: path.to_owned(),
+            version: journal_file.version,
+        });
+    }
+    Ok(journal_file.entry)
+}
+
+/// Returns whether a temporary file was left behind by a process that doesn't exist anymore.
+///
+/// Temporary files are named after the process that created them. Files whose process can't be known
+/// to have exited, such as files created on another host, are never considered stale.
+fn is_stale_temp_file(path: &Path) -> bool {
+    let owner = || {
+        let name = path.file_name()?.to_str()?.strip_prefix(TEMP_FILE_PREFIX)?;
+        let (pid, rest) = name.split_once('-')?;
+        let (host, _random) = rest.rsplit_once('-')?;
+        Some(LockHolder {
+            pid: pid.parse().ok()?,
+            host: host.to_owned(),
+        })
+    };
+    owner().is_some_and(|owner| owner.is_dead())
+}
+
+/// Removes a file, and returns whether it existed.
+fn remove_if_exists(path: &Path) -> Result<bool, DeployError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(DeployError::Remove {
+            path: path.to_owned(),
+            source: err,
+        }),
+    }
+}
+
+/// The result of recovering interrupted operations with [`MediaArchive::recover`].
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// The number of steps of interrupted operations that were finished, such as files moved into the store,
+    /// deployed files that were recorded, and stored files removed by the garbage collector.
+    pub finished: usize,
+    /// The number of steps of interrupted operations that were rolled back, such as partially deployed files
+    /// that were removed.
+    pub rolled_back: usize,
+    /// The number of interrupted operations that couldn't be recovered, because they deployed files to the main
+    /// deployment directory of an archive opened without one. They are recovered once the archive is opened
+    /// with its deployment directory.
+    pub pending: usize,
+    /// The number of interrupted operations that failed to be recovered. They are left in the journal,
+    /// to be recovered again later.
+    pub failed: usize,
+    /// The number of journal entries that couldn't be read, and were set aside.
+    pub set_aside: usize,
+    /// The number of leftover temporary files that were removed.
+    pub removed_temp_files: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum RecoveryError {
+    #[error("failed to remove stored file: {0}")]
+    Blob(#[source] BlobError),
+    #[error("failed to recover deployed files: {0}")]
+    Deploy(#[source] DeployError),
+    #[error("failed to find the stored files that must not be removed: {0}")]
+    Gc(#[source] GcError),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("failed to parse journal entry '{path}': {source}")]
+    Parse { path: PathBuf, source: serde_json::Error },
+    #[error("failed to read journal: {0}")]
+    Read(#[source] io::Error),
+    #[error("failed to remove '{path}': {source}")]
+    Remove { path: PathBuf, source: io::Error },
+    #[error("failed to set aside invalid journal entry '{path}': {source}")]
+    SetAside { path: PathBuf, source: io::Error },
+    #[error("journal entry '{path}' has unsupported version {version}")]
+    UnsupportedVersion { path: PathBuf, version: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+
+    use crate::lock::host_name;
+    use crate::tests::{temp_media_archive, TEST_DATA, TEST_DATA_HASH, ZERO_HASH};
+    use crate::DiskStructure;
+
+    fn journal_len(archive: &MediaArchive) -> usize {
+        archive.journal_entries().unwrap().len()
+    }
+
+    /// Simulates a crash in the middle of an operation, by neither completing nor dropping its record.
+    fn crash(record: JournalRecord) {
+        std::mem::forget(record);
+    }
+
+    #[test]
+    fn interrupted_store() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let source_dir = TempDir::new().unwrap();
+        let source = source_dir.child("file.txt");
+        source.write_str(TEST_DATA).unwrap();
+
+        let hash = Hash::from_hex(TEST_DATA_HASH).unwrap();
+        crash(
+            archive
+                .begin_operation(JournalEntry::Store {
+                    source: source.to_path_buf(),
+                    hash,
+                })
+                .unwrap(),
+        );
+        let stored_path = archive.get_path_of_stored_file(&hash);
+        fs::create_dir_all(stored_path.parent().unwrap()).unwrap();
+        fs::rename(&source, &stored_path).unwrap();
+
+        let lock = archive.lock(LockMode::Exclusive).unwrap();
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        assert_eq!(journal_len(&archive), 1, "recovered while the archive was in use");
+        drop(lock);
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        assert_eq!(journal_len(&archive), 0);
+        assert!(archive.contains(&hash));
+        assert!(fs::metadata(&stored_path).unwrap().permissions().readonly());
+    }
+
+    #[test]
+    fn interrupted_deploy() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        temp_dir.child("existing.txt").write_str(TEST_DATA).unwrap();
+        temp_dir.child("replaced.txt").write_str("user data").unwrap();
+
+        crash(
+            archive
+                .begin_deploy([
+                    (RelativePath::new("complete.txt"), hash, DeployMethod::Auto),
+                    (RelativePath::new("existing.txt"), hash, DeployMethod::Copy),
+                    (RelativePath::new("not-started.txt"), hash, DeployMethod::Copy),
+                    (RelativePath::new("partial.txt"), hash, DeployMethod::Copy),
+                    (RelativePath::new("replaced.txt"), hash, DeployMethod::Copy),
+                ])
+                .unwrap(),
+        );
+        archive
+            .deploy_to(&hash, &temp_dir.child("complete.txt"), DeployMethod::Copy)
+            .unwrap();
+        temp_dir.child("partial.txt").write_str(&TEST_DATA[..3]).unwrap();
+        archive
+            .replace_deployed_file(&hash, &temp_dir.child("replaced.txt"), DeployMethod::Copy)
+            .unwrap();
+
+        let temp_path = temp_dir.child(".media-archive").child(TEMP_DIRECTORY);
+        let stale_temp_file = temp_path.child(format!("{}{}-{}-leftover", TEMP_FILE_PREFIX, u32::MAX, host_name()));
+        stale_temp_file.write_str(TEST_DATA).unwrap();
+        let live_temp_file = temp_path.child(format!(
+            "{}{}-{}-in-use",
+            TEMP_FILE_PREFIX,
+            std::process::id(),
+            host_name()
+        ));
+        live_temp_file.write_str(TEST_DATA).unwrap();
+
+        // Without its deployment directory, the archive can only clean up after the deployment.
+        let bare = MediaArchive::open(archive.archive_path.clone(), DiskStructure::Bare).unwrap();
+        if Path::new("/proc/self").exists() {
+            stale_temp_file.assert(predicates::path::missing());
+        }
+        live_temp_file.assert(TEST_DATA);
+        assert_eq!(bare.recover().unwrap().pending, 1);
+        assert_eq!(journal_len(&bare), 1);
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+        assert_eq!(journal_len(&archive), 0);
+        temp_dir.child("complete.txt").assert(TEST_DATA);
+        temp_dir.child("existing.txt").assert(TEST_DATA);
+        temp_dir.child("not-started.txt").assert(predicates::path::missing());
+        temp_dir.child("partial.txt").assert(predicates::path::missing());
+        temp_dir.child("replaced.txt").assert(TEST_DATA);
+
+        let deployments = archive.deployments().unwrap();
+        assert_eq!(
+            deployments.keys().map(|path| path.as_str()).collect::<Vec<_>>(),
+            ["complete.txt", "replaced.txt"]
+        );
+        assert_eq!(
+            deployments[RelativePath::new("complete.txt")].method,
+            DeployMethod::Copy
+        );
+    }
+
+    #[test]
+    fn interrupted_gc() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let garbage = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let pinned = archive.store_reader(&b"pinned"[..]).unwrap().hash();
+
+        crash(archive.begin_gc(&[garbage, pinned], Duration::ZERO).unwrap());
+        archive.pin(&pinned).unwrap();
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        assert_eq!(journal_len(&archive), 0);
+        assert!(!archive.contains(&garbage));
+        assert!(archive.contains(&pinned));
+    }
+
+    #[test]
+    fn failed_operation() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+
+        assert!(matches!(
+            archive.deploy_file(&ZERO_HASH, RelativePath::new("a.txt"), DeployMethod::Copy),
+            Err(DeployError::NotFound(_))
+        ));
+        assert_eq!(journal_len(&archive), 0);
+        temp_dir.child("a.txt").assert(predicates::path::missing());
+    }
+
+    #[test]
+    fn invalid_journal_entry() {
+        let (temp_dir, _archive) = temp_media_archive(DiskStructure::Bare);
+        let journal_path = temp_dir.child(JOURNAL_DIRECTORY);
+        journal_path.child("0-0-0.json").write_str("{").unwrap();
+        journal_path
+            .child("0-0-1.json")
+            .write_str(r#"{"version":1000,"operation":"gc"}"#)
+            .unwrap();
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        assert_eq!(journal_len(&archive), 0);
+        journal_path.child("0-0-0.invalid").assert("{");
+        journal_path.child("0-0-1.invalid").assert(predicates::path::exists());
+        assert_eq!(archive.recover().unwrap().set_aside, 0);
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info};
+
+use crate::{
+    ArchiveConfig, ConfigError, DeployError, DeployMethod, Hash, ListBlobsError, LockError, LockMode, MediaArchive,
+    STORE_DIRECTORY,
+};
+
+impl MediaArchive {
+    /// Moves every stored file to the directory it belongs in with a different store layout,
+    /// and makes it the archive's layout.
+    ///
+    /// The new layout is recorded before any file is moved, and files that weren't moved yet
+    /// are still found in their previous location, so the archive can be used as usual
+    /// if the migration is interrupted. Calling this function again with the same layout resumes it.
+    /// Symlinks deployed by the archive, in every deployment target, are updated to point to the moved files.
+    ///
+    /// Other handles to the archive, including ones in other processes, still expect the previous layout,
+    /// so they fail to lock the archive with [`LockError::StoreLayoutChanged`] and must be opened again.
+    #[tracing::instrument(skip(self), err)]
+    pub fn migrate_layout(&mut self, layout: StoreLayout) -> Result<MigrateLayoutReport, MigrateLayoutError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(MigrateLayoutError::Lock)?;
+        if !layout.is_valid() {
+            return Err(MigrateLayoutError::InvalidLayout(layout));
+        }
+        match self.config.previous_store_layout {
+            Some(_) if self.config.store_layout != layout => {
+                return Err(MigrateLayoutError::MigrationInProgress(self.config.store_layout));
+            }
+            Some(_) => info!("resuming interrupted store layout migration"),
+            None if self.config.store_layout == layout => return Ok(MigrateLayoutReport::default()),
+            None => {
+                let config = ArchiveConfig {
+                    store_layout: layout,
+                    previous_store_layout: Some(self.config.store_layout),
+                    ..self.config.clone()
+                };
+                self.save_config(config).map_err(MigrateLayoutError::Config)?;
+            }
+        }
+        let previous_layout = self
+            .config
+            .previous_store_layout
+            .expect("a migration should be in progress");
+
+        let store_path = self.archive_path.join(STORE_DIRECTORY);
+        let mut report = MigrateLayoutReport::default();
+        for result in self.blobs() {
+            let blob = match result {
+                Ok(blob) => blob,
+                // Stray and misplaced files aren't moved, they are reported by `MediaArchive::verify`.
+                Err(ListBlobsError::StrayFile(_) | ListBlobsError::Misplaced { .. }) => continue,
+                Err(err) => return Err(MigrateLayoutError::ListBlobs(err)),
+            };
+
+            let from = previous_layout.path_of(&store_path, &blob.hash);
+            let to = layout.path_of(&store_path, &blob.hash);
+            if from == to || !from.is_file() {
+                continue;
+            }
+
+            let parent = to.parent().expect("stored file path should have a parent");
+            fs::create_dir_all(parent).map_err(|err| MigrateLayoutError::CreateParentDir {
+                path: parent.to_owned(),
+                source: err,
+            })?;
+            fs::rename(&from, &to).map_err(|err| MigrateLayoutError::Move {
+                from: from.clone(),
+                to: to.clone(),
+                source: err,
+            })?;
+            debug!("moved '{}' to '{}'", from.display(), to.display());
+            report.moved += 1;
+        }
+        remove_empty_directories(&store_path);
+
+        report.relinked += self.relink_deployed_symlinks(previous_layout)?;
+        for name in self.config.targets.keys() {
+            let target = self.target(name).expect("target should be in the configuration");
+            report.relinked += target.relink_deployed_symlinks(previous_layout)?;
+        }
+
+        let config = ArchiveConfig {
+            previous_store_layout: None,
+            ..self.config.clone()
+        };
+        self.save_config(config).map_err(MigrateLayoutError::Config)?;
+
+        info!(
+            "moved {} stored files and updated {} symlinks to the new store layout",
+            report.moved, report.relinked
+        );
+        Ok(report)
+    }
+
+    /// Fails if the store layout was changed by another handle to the archive since this one loaded its configuration.
+    ///
+    /// Only meaningful while the archive is locked, as the layout can't change until it's unlocked.
+    pub(crate) fn check_store_layout(&self) -> Result<(), LockError> {
+        match crate::config::load_config(&self.archive_path) {
+            Ok(Some(config))
+                if config.store_layout != self.config.store_layout
+                    || config.previous_store_layout != self.config.previous_store_layout =>
+            {
+                Err(LockError::StoreLayoutChanged)
+            }
+            Ok(_) => Ok(()),
+            Err(err) => {
+                debug!("failed to reload configuration to check the store layout: {}", err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Updates the symlinks deployed by the archive that point to the stored file's location
+    /// in `previous_layout`, and returns how many were updated.
+    fn relink_deployed_symlinks(&self, previous_layout: StoreLayout) -> Result<usize, MigrateLayoutError> {
+        if self.deploy_path.is_none() {
+            return Ok(0);
+        }
+
+        let store_path = self.archive_path.join(STORE_DIRECTORY);
+        let mut relinked = 0;
+        let state = self.load_deployment_state().map_err(MigrateLayoutError::Deployments)?;
+        for (path, record) in state.files {
+            if record.method != DeployMethod::Symlink {
+                continue;
+            }
+
+            let target_path = self
+                .deploy_target_path(&path)
+                .map_err(MigrateLayoutError::Deployments)?;
+            let Ok(link_target) = fs::read_link(&target_path) else {
+                continue;
+            };
+            let parent = target_path.parent().expect("target path should have a parent");
+            let previous_link_target = self
+                .symlink_target(&previous_layout.path_of(&store_path, &record.hash), parent)
+                .map_err(MigrateLayoutError::Deployments)?;
+            if link_target != previous_link_target {
+                continue;
+            }
+
+            self.replace_deployed_file(&record.hash, &target_path, DeployMethod::Symlink)
+                .map_err(|err| MigrateLayoutError::Relink {
+                    path: target_path,
+                    source: err,
+                })?;
+            relinked += 1;
+        }
+        Ok(relinked)
+    }
+}
+
+/// How stored files are spread across subdirectories of the store directory.
+///
+/// Each stored file is placed in `subdir_count` nested directories, named after consecutive
+/// `subdir_name_len` character long parts of the file's hash. More directories keep each of them small
+/// in archives with lots of files, while fewer directories suit file systems where they are costly.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StoreLayout {
+    /// How many levels of subdirectories stored files are placed in.
+    pub subdir_count: usize,
+    /// How many characters of the hash each subdirectory is named after.
+    pub subdir_name_len: usize,
+}
+
+impl StoreLayout {
+    /// Returns whether the hash is long enough to name every subdirectory of the layout.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.subdir_count == 0
+            || (self.subdir_name_len > 0
+                && self
+                    .subdir_count
+                    .checked_mul(self.subdir_name_len)
+                    .is_some_and(|len| len <= blake3::OUT_LEN * 2))
+    }
+
+    /// Returns the path of the stored file with the given hash, inside the store directory in `store_path`.
+    pub(crate) fn path_of(&self, store_path: &Path, hash: &Hash) -> PathBuf {
+        let hash = hash.to_hex();
+        let mut path = store_path.to_owned();
+
+        let mut subdir_name_iterator = hash
+            .as_bytes()
+            .chunks_exact(self.subdir_name_len.max(1))
+            .map(|chunk| std::str::from_utf8(chunk).expect("string is ASCII"));
+
+        for _ in 0..self.subdir_count {
+            let subdir = subdir_name_iterator.next().expect("hash length is big enough");
+            path.push(subdir);
+        }
+
+        path.push(hash.as_str());
+        path
+    }
+}
+
+impl Default for StoreLayout {
+    fn default() -> Self {
+        Self {
+            subdir_count: 2,
+            subdir_name_len: 2,
+        }
+    }
+}
+
+/// Removes every empty directory inside `path`, ignoring any errors.
+fn remove_empty_directories(path: &Path) {
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            let subdir = entry.path();
+            remove_empty_directories(&subdir);
+            let _ = fs::remove_dir(subdir);
+        }
+    }
+}
+
+/// The result of migrating the store to a different layout with [`MediaArchive::migrate_layout`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MigrateLayoutReport {
+    /// How many stored files were moved.
+    pub moved: usize,
+    /// How many deployed symlinks were updated to point to the moved files.
+    pub relinked: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum MigrateLayoutError {
+    #[error("failed to save configuration: {0}")]
+    Config(#[source] ConfigError),
+    #[error("failed to create directory '{path}': {source}")]
+    CreateParentDir { path: PathBuf, source: io::Error },
+    #[error("failed to access the record of deployed files: {0}")]
+    Deployments(#[source] DeployError),
+    #[error("store layout {0:?} is not valid")]
+    InvalidLayout(StoreLayout),
+    #[error("failed to list stored files: {0}")]
+    ListBlobs(#[source] ListBlobsError),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("a migration to store layout {0:?} is already in progress")]
+    MigrationInProgress(StoreLayout),
+    #[error("failed to move '{from}' to '{to}': {source}")]
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        source: io::Error,
+    },
+    #[error("failed to update symlink '{path}': {source}")]
+    Relink { path: PathBuf, source: DeployError },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+    use predicates::prelude::*;
+    use relative_path::RelativePath;
+
+    use crate::tests::{temp_media_archive, TEST_DATA, TEST_DATA_HASH};
+    use crate::{BlobError, DiskStructure, StoreBlobError};
+
+    const FLAT_LAYOUT: StoreLayout = StoreLayout {
+        subdir_count: 1,
+        subdir_name_len: 3,
+    };
+
+    #[test]
+    fn migrate_layout() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let other_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+        archive
+            .deploy_file(&hash, RelativePath::new("a/link.txt"), DeployMethod::Symlink)
+            .unwrap();
+        archive
+            .deploy_file(&other_hash, RelativePath::new("copy.txt"), DeployMethod::Copy)
+            .unwrap();
+
+        assert!(matches!(
+            archive.migrate_layout(StoreLayout {
+                subdir_count: 33,
+                subdir_name_len: 2,
+            }),
+            Err(MigrateLayoutError::InvalidLayout(_))
+        ));
+
+        let report = archive.migrate_layout(FLAT_LAYOUT).unwrap();
+        assert_eq!(report.moved, 2);
+        assert_eq!(report.relinked, 1);
+
+        let store = temp_dir.child(".media-archive").child(STORE_DIRECTORY);
+        store
+            .child(&TEST_DATA_HASH[..3])
+            .child(TEST_DATA_HASH)
+            .assert(TEST_DATA);
+        store.child(&TEST_DATA_HASH[..2]).assert(predicate::path::missing());
+        temp_dir.child("a/link.txt").assert(TEST_DATA);
+        assert!(archive.status().unwrap().files.is_empty());
+        assert_eq!(archive.blobs().flatten().count(), 2);
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+        assert_eq!(archive.config().store_layout, FLAT_LAYOUT);
+        assert_eq!(archive.config().previous_store_layout, None);
+        assert!(archive.contains(&hash));
+    }
+
+    #[test]
+    fn resume_migrate_layout() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        // Record a migration without moving any file, as if it was interrupted right away.
+        let config = ArchiveConfig {
+            store_layout: FLAT_LAYOUT,
+            previous_store_layout: Some(StoreLayout::default()),
+            ..archive.config().clone()
+        };
+        archive.save_config(config).unwrap();
+        assert!(matches!(
+            archive.set_config(ArchiveConfig::default()),
+            Err(ConfigError::StoreLayoutChanged)
+        ));
+
+        let mut archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        assert!(archive.contains(&hash));
+        assert_eq!(archive.blobs().flatten().count(), 1);
+        let other_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+
+        assert!(matches!(
+            archive.migrate_layout(StoreLayout::default()),
+            Err(MigrateLayoutError::MigrationInProgress(FLAT_LAYOUT))
+        ));
+        let report = archive.migrate_layout(FLAT_LAYOUT).unwrap();
+        assert_eq!(report.moved, 1);
+        assert!(archive.contains(&hash));
+        assert!(archive.contains(&other_hash));
+        temp_dir
+            .child(STORE_DIRECTORY)
+            .child(&TEST_DATA_HASH[..3])
+            .child(TEST_DATA_HASH)
+            .assert(TEST_DATA);
+    }
+
+    #[test]
+    fn migrate_layout_with_other_handle() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Bare);
+        let stored_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+        let other_archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        archive.migrate_layout(FLAT_LAYOUT).unwrap();
+
+        assert!(matches!(
+            other_archive.blob_path(&stored_hash),
+            Err(BlobError::Lock(LockError::StoreLayoutChanged))
+        ));
+        assert!(!other_archive.contains(&stored_hash));
+
+        assert!(matches!(
+            other_archive.store_reader(TEST_DATA.as_bytes()),
+            Err(StoreBlobError::Lock(LockError::StoreLayoutChanged))
+        ));
+        temp_dir
+            .child(STORE_DIRECTORY)
+            .child(&TEST_DATA_HASH[..2])
+            .assert(predicate::path::missing());
+
+        let other_archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        let hash = other_archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        assert!(archive.contains(&hash));
+    }
+}
@@ -15,47 +15,201 @@
 
 #![forbid(unsafe_code)]
 
+mod adopt;
+mod blobs;
+mod config;
+mod content_id;
+mod deploy;
+mod deployment;
+mod gc;
+mod journal;
+mod layout;
+mod lock;
+mod manifest;
+mod status;
+mod sync;
+mod targets;
+mod verify;
+
+use std::collections::BTreeMap;
 use std::fs::{self, File};
-use std::io;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
 
 use relative_path::{PathExt, RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+use tempfile::{NamedTempFile, TempPath};
 use thiserror::Error;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
+use crate::content_id::ContentHasher;
+use crate::journal::JournalEntry;
+use crate::lock::{ArchiveLockState, DEFAULT_LOCK_TIMEOUT};
+
+pub use adopt::{AdoptError, AdoptOptions, AdoptReport};
 pub use blake3::Hash;
+pub use blobs::{BlobInfo, Blobs, ListBlobsError};
+pub use config::{ArchiveConfig, ConfigError, SymlinkStyle};
+pub use content_id::{ContentId, HashAlgorithm, LookupError, ParseContentIdError};
+pub use deploy::{DeployManifestReport, DeployOutcome, ExistingFilePolicy};
+pub use deployment::{DeploymentRecord, UndeployReport};
+pub use gc::{GcError, GcOptions, GcProblem, GcReport, PinError};
+pub use journal::{RecoveryError, RecoveryReport};
+pub use layout::{MigrateLayoutError, MigrateLayoutReport, StoreLayout};
+pub use lock::{ArchiveLock, LockError, LockHolder, LockMode};
+pub use manifest::{Manifest, ManifestEntry, ManifestError};
+pub use status::{FileStatus, StatusReport};
+pub use sync::{SyncChange, SyncOptions, SyncReport};
+pub use targets::{DeploymentTarget, TargetError};
+pub use verify::{VerifyError, VerifyOptions, VerifyProblem, VerifyReport};
 
 const MEDIA_ARCHIVE_DIRECTORY: &str = ".media-archive";
 const STORE_DIRECTORY: &str = "store";
+const TEMP_DIRECTORY: &str = "tmp";
+const TEMP_FILE_PREFIX: &str = "store-";
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+const DEFAULT_AUTO_DEPLOY_METHODS: &[DeployMethod] = &[DeployMethod::Hardlink, DeployMethod::Copy];
 
 #[derive(Debug)]
 pub struct MediaArchive {
     archive_path: PathBuf,
     deploy_path: Option<PathBuf>,
+    config: ArchiveConfig,
+    auto_deploy_methods: Vec<DeployMethod>,
+    /// The name of the deployment target the archive deploys to, if it's not the main deployment directory.
+    target: Option<String>,
+    lock: Arc<ArchiveLockState>,
+    lock_timeout: Duration,
 }
 
 impl MediaArchive {
+    /// Creates a new media archive in a directory, and opens it.
+    ///
+    /// The directory will be created if it doesn't already exist. The archive's configuration file,
+    /// which records the version of the archive's on-disk format, is written to the archive directory.
+    /// Fails if the directory already contains an archive.
+    ///
+    /// See [`MediaArchive::open`] for the meaning of `disk_structure`. Archives can't be created with
+    /// [`DiskStructure::Detached`], since there is no deployment directory recorded yet; create a bare archive
+    /// and open it with [`MediaArchive::open_with_deploy_path`] instead.
+    #[tracing::instrument(err)]
+    pub fn init(path: PathBuf, disk_structure: DiskStructure) -> Result<Self, OpenMediaArchiveError> {
+        let (archive_path, deploy_path) = match disk_structure {
+            DiskStructure::Bare => (path, None),
+            DiskStructure::Deployable => (path.join(MEDIA_ARCHIVE_DIRECTORY), Some(path)),
+            DiskStructure::Detached => return Err(OpenMediaArchiveError::NoDeployPath),
+        };
+        fs::create_dir_all(&archive_path).map_err(OpenMediaArchiveError::CreateDir)?;
+        if archive_path
+            .join(config::CONFIG_FILE)
+            .try_exists()
+            .map_err(OpenMediaArchiveError::CreateDir)?
+        {
+            return Err(OpenMediaArchiveError::AlreadyExists(archive_path));
+        }
+
+        let mut archive = Self {
+            archive_path,
+            deploy_path,
+            config: ArchiveConfig::default(),
+            auto_deploy_methods: DEFAULT_AUTO_DEPLOY_METHODS.to_vec(),
+            target: None,
+            lock: Arc::default(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        };
+        archive
+            .set_config(ArchiveConfig::default())
+            .map_err(OpenMediaArchiveError::Config)?;
+        info!("created media archive successfully");
+        Ok(archive)
+    }
+
     /// Opens a directory as a media archive.
     ///
-    /// The directory will be created if it doesn't already exist,
-    /// and so will any needed media archive files.
+    /// The archive must have been created with [`MediaArchive::init`]. Directories that aren't archives,
+    /// and archives with an unsupported on-disk format version, are refused. Archives created before
+    /// the on-disk format was versioned, which have a store directory but no configuration file,
+    /// are upgraded by saving the default configuration in them.
     ///
     /// If `disk_structure` is [`DiskStructure::Deployable`], an archive directory will be created inside `path`,
     /// and `path` will be the directory where media files are deployed to.
     /// If `disk_structure` is [`DiskStructure::Bare`], no media files will be deployed, and `path`
     /// will be treated as the archive directory (similar to Git's bare repositories).
+    /// If `disk_structure` is [`DiskStructure::Detached`], `path` will be treated as the archive directory,
+    /// and media files will be deployed to the directory recorded by [`MediaArchive::open_with_deploy_path`].
+    ///
+    /// Operations that were interrupted, such as by a crash or a power loss, are recovered,
+    /// unless the archive is in use. See [`MediaArchive::recover`].
     #[tracing::instrument(err)]
     pub fn open(path: PathBuf, disk_structure: DiskStructure) -> Result<Self, OpenMediaArchiveError> {
-        let (archive_path, deploy_path) = match disk_structure {
-            DiskStructure::Bare => (path, None),
-            DiskStructure::Deployable => (path.join(MEDIA_ARCHIVE_DIRECTORY), Some(path)),
+        let archive_path = match disk_structure {
+            DiskStructure::Bare | DiskStructure::Detached => path.clone(),
+            DiskStructure::Deployable => path.join(MEDIA_ARCHIVE_DIRECTORY),
+        };
+        let (config, unversioned) = match config::load_config(&archive_path).map_err(OpenMediaArchiveError::Config)? {
+            Some(config) => (config, false),
+            None if archive_path.join(STORE_DIRECTORY).is_dir() => (ArchiveConfig::default(), true),
+            None => return Err(OpenMediaArchiveError::NotAnArchive(archive_path)),
+        };
+
+        let deploy_path = match disk_structure {
+            DiskStructure::Bare => None,
+            DiskStructure::Deployable => Some(path),
+            DiskStructure::Detached => Some(config.deploy_path.clone().ok_or(OpenMediaArchiveError::NoDeployPath)?),
         };
-        fs::create_dir_all(&archive_path).map_err(OpenMediaArchiveError::CreateDir)?;
 
-        Ok(Self {
+        let mut archive = Self {
             archive_path,
             deploy_path,
-        })
+            config,
+            auto_deploy_methods: DEFAULT_AUTO_DEPLOY_METHODS.to_vec(),
+            target: None,
+            lock: Arc::default(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        };
+        if unversioned {
+            info!("upgrading media archive without a configuration file");
+            archive
+                .save_config(ArchiveConfig::default())
+                .map_err(OpenMediaArchiveError::Config)?;
+        }
+        archive.recover_on_open();
+        Ok(archive)
+    }
+
+    /// Opens a directory as a media archive that deploys media files to a separate directory,
+    /// such as one on a different disk.
+    ///
+    /// The archive must already exist, but the deployment directory will be created if it doesn't. It is
+    /// recorded in the archive's configuration, so that the archive can later be opened with
+    /// [`DiskStructure::Detached`] without knowing where it is.
+    #[tracing::instrument(err)]
+    pub fn open_with_deploy_path(archive_path: PathBuf, deploy_path: PathBuf) -> Result<Self, OpenMediaArchiveError> {
+        let deploy_path = std::path::absolute(&deploy_path).map_err(OpenMediaArchiveError::CreateDir)?;
+        fs::create_dir_all(&deploy_path).map_err(OpenMediaArchiveError::CreateDir)?;
+
+        let mut archive = Self::open(archive_path, DiskStructure::Bare)?;
+        if archive.config.deploy_path.as_ref() != Some(&deploy_path) {
+            let config = ArchiveConfig {
+                deploy_path: Some(deploy_path.clone()),
+                ..archive.config.clone()
+            };
+            archive.set_config(config).map_err(OpenMediaArchiveError::Config)?;
+        }
+        archive.deploy_path = Some(deploy_path);
+        Ok(archive)
+    }
+
+    /// Sets the methods tried, in order, when deploying files with [`DeployMethod::Auto`].
+    ///
+    /// The default is [`DeployMethod::Hardlink`], followed by [`DeployMethod::Copy`].
+    /// [`DeployMethod::Auto`] itself is ignored if present.
+    pub fn set_auto_deploy_methods(&mut self, methods: Vec<DeployMethod>) {
+        self.auto_deploy_methods = methods;
     }
 
     /// Returns the path to a stored file from its hash.
@@ -63,25 +217,18 @@ impl MediaArchive {
     /// The file does not need to exist.
     #[must_use]
     fn get_path_of_stored_file(&self, hash: &Hash) -> PathBuf {
-        const SUBDIR_COUNT: usize = 2;
-        const SUBDIR_NAME_LEN: usize = 2;
-        const _: () = assert!(SUBDIR_COUNT * SUBDIR_NAME_LEN <= blake3::OUT_LEN * 2);
-
-        let hash = hash.to_hex();
-        let mut path = self.archive_path.clone();
-        path.push(STORE_DIRECTORY);
-
-        let mut subdir_name_iterator = hash
-            .as_bytes()
-            .chunks_exact(SUBDIR_NAME_LEN)
-            .map(|chunk| std::str::from_utf8(chunk).expect("string is ASCII"));
-
-        for _ in 0..SUBDIR_COUNT {
-            let subdir = subdir_name_iterator.next().expect("hash length is big enough");
-            path.push(subdir);
+        let store_path = self.archive_path.join(STORE_DIRECTORY);
+        let path = self.config.store_layout.path_of(&store_path, hash);
+
+        // While the store is being migrated to a different layout, the file may not have been moved yet.
+        if let Some(previous_layout) = self.config.previous_store_layout {
+            if !path.exists() {
+                let previous_path = previous_layout.path_of(&store_path, hash);
+                if previous_path.exists() {
+                    return previous_path;
+                }
+            }
         }
-
-        path.push(hash.as_str());
         path
     }
 
@@ -89,77 +236,337 @@ impl MediaArchive {
     ///
     /// Files in the archive are identified by their hash value, and this function will return
     /// this value after storing the file.
+    ///
+    /// If a file with the same contents is already stored, nothing is stored, and
+    /// [`StoreOutcome::Deduplicated`] is returned instead of [`StoreOutcome::Stored`].
+    /// If the file was reflinked into the store, [`StoreOutcome::Reflinked`] is returned.
+    ///
+    /// When copying, the file is written to a temporary file first, and only moved into the store
+    /// once it's complete, so an interrupted store never leaves a partially written file in the store.
     #[tracing::instrument(skip(self), err)]
-    pub fn store_file(&self, path: &Path, method: StoreMethod) -> Result<Hash, StoreFileError> {
+    pub fn store_file(&self, path: &Path, method: StoreMethod) -> Result<StoreOutcome, StoreFileError> {
+        let _lock = self.lock(LockMode::Shared).map_err(StoreFileError::Lock)?;
         let metadata = path.symlink_metadata().map_err(StoreFileError::Metadata)?;
         if metadata.is_dir() {
             return Err(StoreFileError::IsDirectory);
         }
-        if metadata.is_symlink() && method == StoreMethod::Move {
+        if metadata.is_symlink() && method.is_move() {
             return Err(StoreFileError::IsSymlink);
         }
         if metadata.is_symlink() && path.metadata().map_err(StoreFileError::Metadata)?.is_dir() {
             return Err(StoreFileError::IsDirectory);
         }
 
-        let hash = {
-            let file = File::open(path).map_err(StoreFileError::Open)?;
-            let mut hasher = blake3::Hasher::new();
-            hasher.update_reader(file).map_err(StoreFileError::Read)?;
-            hasher.finalize()
-        };
+        if matches!(method, StoreMethod::Copy | StoreMethod::Reflink) {
+            return match self.reflink_to_temp_file(path) {
+                Ok((hash, ids, temp_path)) => match self.commit_temp_file(temp_path, hash, &ids)? {
+                    StoreOutcome::Stored(hash) => Ok(StoreOutcome::Reflinked(hash)),
+                    outcome => Ok(outcome),
+                },
+                Err(StoreFileError::Reflink(err)) if method == StoreMethod::Copy => {
+                    debug!("failed to reflink file, falling back to copying it: {}", err);
+                    Ok(self.store_reader(File::open(path).map_err(StoreFileError::Open)?)?)
+                }
+                Err(err) => Err(err),
+            };
+        }
+
+        let mut hasher = self.content_hasher();
+        File::open(path)
+            .and_then(|file| hasher.update_reader(file))
+            .map_err(StoreFileError::Read)?;
+        let (hash, ids) = hasher.finalize();
 
         let target_path = self.get_path_of_stored_file(&hash);
         if target_path.exists() {
-            return Err(StoreFileError::AlreadyExists(hash));
+            if method == StoreMethod::MoveOrDelete {
+                if !files_are_equal(path, &target_path).map_err(StoreFileError::Verify)? {
+                    return Err(StoreFileError::DuplicateMismatch(hash));
+                }
+                fs::remove_file(path).map_err(StoreFileError::RemoveSource)?;
+                info!("file already stored, removed duplicate");
+            } else {
+                info!("file already stored");
+            }
+            touch(&target_path);
+            self.record_digests(&hash, &ids);
+            return Ok(StoreOutcome::Deduplicated(hash));
         }
 
+        let journal = self
+            .begin_operation(JournalEntry::Store {
+                source: path.to_owned(),
+                hash,
+            })
+            .map_err(StoreFileError::Journal)?;
         let parent = target_path.parent().expect("target path should have a parent");
         fs::create_dir_all(parent).map_err(StoreFileError::CreateParentDir)?;
+        fs::rename(path, &target_path).map_err(StoreFileError::Store)?;
+        sync_parent_dir(&target_path).map_err(StoreFileError::Store)?;
+        touch(&target_path);
+        set_readonly(&target_path);
+        self.record_digests(&hash, &ids);
+        journal.complete();
 
-        match method {
-            StoreMethod::Copy => {
-                reflink_copy::reflink_or_copy(path, &target_path).map_err(StoreFileError::Store)?;
-            }
-            StoreMethod::Move => {
-                fs::rename(path, &target_path).map_err(StoreFileError::Store)?;
+        info!("stored file successfully");
+        Ok(StoreOutcome::Stored(hash))
+    }
+
+    /// Stores the data read from `reader` in the archive.
+    ///
+    /// This is the equivalent of [`MediaArchive::store_file`] with [`StoreMethod::Copy`]
+    /// for data that isn't in a file, such as a download in progress.
+    #[tracing::instrument(skip_all, err)]
+    pub fn store_reader(&self, mut reader: impl Read) -> Result<StoreOutcome, StoreBlobError> {
+        let mut writer = self.blob_writer()?;
+        let mut buffer = vec![0; COPY_BUFFER_SIZE];
+        loop {
+            let len = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(StoreBlobError::Read(err)),
+            };
+            writer.write_all(&buffer[..len]).map_err(StoreBlobError::Write)?;
+        }
+        writer.finish()
+    }
+
+    /// Returns a writer that stores the data written to it in the archive.
+    ///
+    /// The data is only stored once [`BlobWriter::finish`] is called.
+    /// If the writer is dropped before that, the data written to it is discarded.
+    pub fn blob_writer(&self) -> Result<BlobWriter<'_>, StoreBlobError> {
+        let lock = self.lock(LockMode::Shared).map_err(StoreBlobError::Lock)?;
+        let temp_dir = self.archive_path.join(TEMP_DIRECTORY);
+        fs::create_dir_all(&temp_dir).map_err(StoreBlobError::CreateTempFile)?;
+        let temp_file = temp_file_builder()
+            .tempfile_in(&temp_dir)
+            .map_err(StoreBlobError::CreateTempFile)?;
+
+        Ok(BlobWriter {
+            archive: self,
+            temp_file,
+            hasher: self.content_hasher(),
+            _lock: lock,
+        })
+    }
+
+    /// Reflinks a file to a temporary file inside the archive, and returns its hash and other digests.
+    ///
+    /// Fails with [`StoreFileError::Reflink`] if reflinks aren't supported by the file system.
+    /// The temporary file is synced to disk, so that it can be atomically moved into the store.
+    fn reflink_to_temp_file(&self, path: &Path) -> Result<(Hash, Vec<ContentId>, TempPath), StoreFileError> {
+        let temp_dir = self.archive_path.join(TEMP_DIRECTORY);
+        fs::create_dir_all(&temp_dir).map_err(StoreFileError::CreateTempFile)?;
+
+        let temp_path = temp_file_builder()
+            .make_in(&temp_dir, |temp_path| reflink_copy::reflink(path, temp_path))
+            .map_err(StoreFileError::Reflink)?
+            .into_temp_path();
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(&temp_path)
+            .map_err(StoreFileError::Open)?;
+        let mut hasher = self.content_hasher();
+        hasher.update_reader(&mut file).map_err(StoreFileError::Read)?;
+        file.sync_all().map_err(StoreFileError::Store)?;
+
+        let (hash, ids) = hasher.finalize();
+        Ok((hash, ids, temp_path))
+    }
+
+    /// Atomically moves a temporary file with the given hash and other digests into the store.
+    fn commit_temp_file(
+        &self,
+        temp_path: TempPath,
+        hash: Hash,
+        ids: &[ContentId],
+    ) -> Result<StoreOutcome, StoreBlobError> {
+        let target_path = self.get_path_of_stored_file(&hash);
+        if target_path.exists() {
+            info!("file already stored");
+            touch(&target_path);
+            self.record_digests(&hash, ids);
+            return Ok(StoreOutcome::Deduplicated(hash));
+        }
+
+        let parent = target_path.parent().expect("target path should have a parent");
+        fs::create_dir_all(parent).map_err(StoreBlobError::CreateParentDir)?;
+
+        match temp_path.persist_noclobber(&target_path) {
+            Ok(()) => (),
+            Err(err) if err.error.kind() == io::ErrorKind::AlreadyExists => {
+                info!("file already stored");
+                touch(&target_path);
+                self.record_digests(&hash, ids);
+                return Ok(StoreOutcome::Deduplicated(hash));
             }
+            Err(err) => return Err(StoreBlobError::Store(err.error)),
+        }
+        set_readonly(&target_path);
+        sync_parent_dir(&target_path).map_err(StoreBlobError::Store)?;
+        self.record_digests(&hash, ids);
+
+        info!("stored file successfully");
+        Ok(StoreOutcome::Stored(hash))
+    }
+
+    /// Stores every regular file inside a directory in the archive.
+    ///
+    /// The directory is walked recursively. Symlinks and other special files are skipped,
+    /// and so is the archive directory, if it is inside `path`.
+    ///
+    /// Failing to store a file doesn't abort the operation. Instead, the error is collected
+    /// in the returned [`StoredDirectory`], alongside the hashes of the files that were stored.
+    #[tracing::instrument(skip(self), err)]
+    pub fn store_directory(&self, path: &Path, method: StoreMethod) -> Result<StoredDirectory, StoreDirectoryError> {
+        let _lock = self.lock(LockMode::Shared).map_err(StoreDirectoryError::Lock)?;
+        let metadata = path.metadata().map_err(StoreDirectoryError::Metadata)?;
+        if !metadata.is_dir() {
+            return Err(StoreDirectoryError::NotADirectory);
         }
 
-        match fs::metadata(&target_path) {
-            Ok(metadata) => {
-                let mut permissions = metadata.permissions();
-                permissions.set_readonly(true);
-                if let Err(err) = fs::set_permissions(&target_path, permissions) {
-                    warn!("failed to set file '{}' as read only: {}", target_path.display(), err);
+        let canonical_archive_path = fs::canonicalize(&self.archive_path).ok();
+        let mut stored = StoredDirectory::default();
+        let mut pending_directories = vec![path.to_owned()];
+
+        while let Some(directory) = pending_directories.pop() {
+            let entries = match fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    stored.errors.push(StoreDirectoryEntryError::ReadDir {
+                        path: directory,
+                        source: err,
+                    });
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let (entry_path, file_type) = match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        stored.errors.push(StoreDirectoryEntryError::ReadDir {
+                            path: directory.clone(),
+                            source: err,
+                        });
+                        continue;
+                    }
+                };
+
+                if file_type.is_dir() {
+                    if canonical_archive_path.is_some() && fs::canonicalize(&entry_path).ok() == canonical_archive_path
+                    {
+                        info!("skipping archive directory '{}'", entry_path.display());
+                    } else {
+                        pending_directories.push(entry_path);
+                    }
+                    continue;
+                }
+                if !file_type.is_file() {
+                    info!("skipping '{}', as it is not a regular file", entry_path.display());
+                    continue;
+                }
+
+                let Some(relative_path) = entry_path
+                    .strip_prefix(path)
+                    .ok()
+                    .and_then(|relative_path| RelativePathBuf::from_path(relative_path).ok())
+                else {
+                    stored.errors.push(StoreDirectoryEntryError::InvalidPath(entry_path));
+                    continue;
+                };
+
+                match self.store_file(&entry_path, method) {
+                    Ok(outcome) => {
+                        stored.files.insert(relative_path, outcome.hash());
+                    }
+                    Err(err) => stored.errors.push(StoreDirectoryEntryError::Store {
+                        path: entry_path,
+                        source: err,
+                    }),
                 }
             }
-            Err(err) => warn!("failed to get metadata of file '{}': {}", target_path.display(), err),
         }
 
-        info!("stored file successfully");
-        Ok(hash)
+        info!(
+            "stored {} files, failed to store {} files",
+            stored.files.len(),
+            stored.errors.len()
+        );
+        Ok(stored)
     }
 
-    /// Deploys a file with the given hash to the deployment directory.
+    /// Returns whether a file with the given hash is stored in the archive.
+    ///
+    /// Returns `false` if the archive can't be locked, such as after its store layout was changed
+    /// by another handle. Use [`MediaArchive::blob_path`] to tell these cases apart.
+    #[must_use]
+    pub fn contains(&self, hash: &Hash) -> bool {
+        match self.stored_file_metadata(hash) {
+            Ok(_) => true,
+            Err(BlobError::Lock(err)) => {
+                warn!(
+                    "failed to lock the archive to look for file with hash '{}': {}",
+                    hash, err
+                );
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the path to the stored file with the given hash.
+    ///
+    /// The stored file must not be modified, as that would corrupt the archive.
+    pub fn blob_path(&self, hash: &Hash) -> Result<PathBuf, BlobError> {
+        self.stored_file_metadata(hash).map(|(path, _)| path)
+    }
+
+    /// Returns the size of the stored file with the given hash.
+    pub fn blob_len(&self, hash: &Hash) -> Result<u64, BlobError> {
+        self.stored_file_metadata(hash).map(|(_, metadata)| metadata.len())
+    }
+
+    /// Opens the stored file with the given hash for reading.
+    pub fn open_blob(&self, hash: &Hash) -> Result<File, BlobError> {
+        let (path, _) = self.stored_file_metadata(hash)?;
+        File::open(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => BlobError::NotFound(*hash),
+            _ => BlobError::Open { path, source: err },
+        })
+    }
+
+    /// Returns the path and metadata of the stored file with the given hash.
+    fn stored_file_metadata(&self, hash: &Hash) -> Result<(PathBuf, fs::Metadata), BlobError> {
+        // The archive is locked to make sure the store layout is up to date.
+        let _lock = self.lock(LockMode::Shared).map_err(BlobError::Lock)?;
+        let path = self.get_path_of_stored_file(hash);
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_file() => Ok((path, metadata)),
+            Ok(_) => Err(BlobError::NotAFile(path)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Err(BlobError::NotFound(*hash)),
+            Err(err) => Err(BlobError::Metadata { path, source: err }),
+        }
+    }
+
+    /// Deploys a file with the given hash to the deployment directory, and returns the method used.
     ///
     /// `target_path` is a relative path from the root of the deployment directory.
+    /// The deployed file is recorded, so that it can later be updated by [`MediaArchive::sync_deployment`].
     #[tracing::instrument(skip(self), err)]
     pub fn deploy_file(
         &self,
         hash: &Hash,
         target_path: &RelativePath,
         method: DeployMethod,
-    ) -> Result<(), DeployError> {
-        let deploy_path = self.deploy_path.as_ref().ok_or(DeployError::IsBareArchive)?;
-
-        let target_path = {
-            let full_target_path = target_path.to_logical_path(deploy_path);
-            if !full_target_path.starts_with(deploy_path) || &full_target_path == deploy_path {
-                return Err(DeployError::InvalidPath(target_path.to_owned()));
-            }
-            full_target_path
-        };
+    ) -> Result<DeployMethod, DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        let relative_target_path = target_path;
+        let target_path = self.deploy_target_path(target_path)?;
 
         match target_path.symlink_metadata() {
             Ok(_) => return Err(DeployError::AlreadyExists(target_path)),
@@ -172,12 +579,77 @@ impl MediaArchive {
             }
         }
 
+        let journal = self.begin_deploy([(relative_target_path, *hash, method)])?;
+        let method = self.deploy_to(hash, &target_path, method)?;
+        self.record_deployed_file(relative_target_path, *hash, method)?;
+        journal.complete();
+        Ok(method)
+    }
+
+    /// Returns the full path of a path relative to the root of the deployment directory.
+    ///
+    /// Paths inside the archive directory are refused, so that deploying can never overwrite the archive's
+    /// own files, such as its configuration or the stored files.
+    pub(crate) fn deploy_target_path(&self, target_path: &RelativePath) -> Result<PathBuf, DeployError> {
+        let deploy_path = self.deploy_path.as_ref().ok_or(DeployError::IsBareArchive)?;
+
+        let full_target_path = target_path.to_logical_path(deploy_path);
+        if !full_target_path.starts_with(deploy_path) || &full_target_path == deploy_path {
+            return Err(DeployError::InvalidPath(target_path.to_owned()));
+        }
+        if full_target_path.starts_with(&self.archive_path) {
+            return Err(DeployError::InsideArchive(target_path.to_owned()));
+        }
+        Ok(full_target_path)
+    }
+
+    /// Deploys a file with the given hash to `target_path`, which must not exist, and returns the method used.
+    pub(crate) fn deploy_to(
+        &self,
+        hash: &Hash,
+        target_path: &Path,
+        method: DeployMethod,
+    ) -> Result<DeployMethod, DeployError> {
+        if method != DeployMethod::Auto {
+            return self.deploy_with_method(hash, target_path, method);
+        }
+
+        let mut last_error = DeployError::NotSupported;
+        for &method in &self.auto_deploy_methods {
+            if method == DeployMethod::Auto {
+                continue;
+            }
+            match self.deploy_with_method(hash, target_path, method) {
+                Ok(method) => return Ok(method),
+                Err(err) if err.is_unsupported() => {
+                    debug!(
+                        "failed to deploy file with method {:?}, trying the next one: {}",
+                        method, err
+                    );
+                    last_error = err;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Deploys a file with a specific method, and returns the method used.
+    ///
+    /// [`DeployMethod::Copy`] is reported as [`DeployMethod::Reflink`] if the file was reflinked.
+    fn deploy_with_method(
+        &self,
+        hash: &Hash,
+        target_path: &Path,
+        mut method: DeployMethod,
+    ) -> Result<DeployMethod, DeployError> {
         let source_path = self.get_path_of_stored_file(hash);
         match source_path.symlink_metadata() {
             Ok(metadata) if !metadata.is_file() => {
                 return Err(DeployError::SourceExistsButIsNotAFile(source_path));
             }
             Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(DeployError::NotFound(*hash)),
             Err(err) => {
                 return Err(DeployError::Metadata {
                     path: source_path,
@@ -190,47 +662,205 @@ impl MediaArchive {
         fs::create_dir_all(parent).map_err(DeployError::CreateParentDir)?;
 
         let result = match method {
-            DeployMethod::Copy => reflink_copy::reflink_or_copy(&source_path, &target_path).and(Ok(())),
+            DeployMethod::Copy => match reflink_copy::reflink_or_copy(&source_path, target_path) {
+                Ok(None) => {
+                    method = DeployMethod::Reflink;
+                    Ok(())
+                }
+                result => result.and(Ok(())),
+            },
+            DeployMethod::Reflink => reflink_copy::reflink(&source_path, target_path),
             DeployMethod::Symlink => {
                 #[cfg(any(target_family = "windows", target_family = "unix"))]
                 {
-                    let relative_source_path = source_path
-                        .relative_to(parent)
-                        .map_err(|source| DeployError::SymlinkRelativePathConstruction {
-                            source_path: source_path.clone(),
-                            target_parent: parent.to_owned(),
-                            source,
-                        })?
-                        .to_path("");
+                    let link_target = self.symlink_target(&source_path, parent)?;
 
                     #[cfg(target_family = "unix")]
                     {
-                        std::os::unix::fs::symlink(&relative_source_path, &target_path)
+                        std::os::unix::fs::symlink(&link_target, target_path)
                     }
                     #[cfg(target_family = "windows")]
                     {
-                        std::os::windows::fs::symlink_file(&relative_source_path, &target_path)
+                        std::os::windows::fs::symlink_file(&link_target, target_path)
                     }
                 }
                 #[cfg(all(not(target_family = "windows"), not(target_family = "unix")))]
                 return Err(DeployError::NotSupported);
             }
-            DeployMethod::Hardlink => fs::hard_link(&source_path, &target_path),
+            DeployMethod::Hardlink => fs::hard_link(&source_path, target_path),
+            DeployMethod::Auto => unreachable!("automatic deployment is resolved to a specific method"),
         };
 
         match result {
             Ok(()) => {
                 info!("deployed file successfully");
-                Ok(())
+                Ok(method)
             }
             Err(err) if err.kind() == io::ErrorKind::Unsupported => Err(DeployError::NotSupported),
+            // Some file systems reject reflinks as an invalid operation instead.
+            Err(err) if method == DeployMethod::Reflink && err.kind() == io::ErrorKind::InvalidInput => {
+                Err(DeployError::NotSupported)
+            }
             Err(err) => Err(DeployError::Deploy {
                 from: source_path,
-                to: target_path,
+                to: target_path.to_owned(),
                 source: err,
             }),
         }
     }
+
+    /// Returns the path a symlink in `parent` should point to, to point to the stored file in `source_path`.
+    ///
+    /// The kind of path depends on the archive's [`SymlinkStyle`].
+    fn symlink_target(&self, source_path: &Path, parent: &Path) -> Result<PathBuf, DeployError> {
+        match &self.config.symlink_style {
+            SymlinkStyle::Relative => Ok(source_path
+                .relative_to(parent)
+                .map_err(|source| DeployError::SymlinkRelativePathConstruction {
+                    source_path: source_path.to_owned(),
+                    target_parent: parent.to_owned(),
+                    source,
+                })?
+                .to_path("")),
+            SymlinkStyle::Absolute => {
+                std::path::absolute(source_path).map_err(|source| DeployError::SymlinkAbsolutePathConstruction {
+                    source_path: source_path.to_owned(),
+                    source,
+                })
+            }
+            SymlinkStyle::Rooted { root } => {
+                let path_in_store = source_path
+                    .strip_prefix(self.archive_path.join(STORE_DIRECTORY))
+                    .expect("stored files should be inside the store directory");
+                Ok(root.join(path_in_store))
+            }
+        }
+    }
+
+    /// Atomically replaces the contents of a file, such as one of the archive's state files.
+    ///
+    /// The contents are written to a temporary file inside the archive, which is then renamed over `path`.
+    fn write_file_atomically(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let temp_dir = self.archive_path.join(TEMP_DIRECTORY);
+        fs::create_dir_all(&temp_dir)?;
+        let mut temp_file = temp_file_builder().tempfile_in(&temp_dir)?;
+        temp_file.write_all(contents)?;
+        temp_file.as_file().sync_all()?;
+        temp_file.persist(path).map_err(|err| err.error)?;
+        sync_parent_dir(path)
+    }
+}
+
+/// Syncs the directory containing `path` to disk, so that a file renamed into it survives a crash.
+#[cfg(target_family = "unix")]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    File::open(path.parent().expect("path should have a parent"))?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms, so renames are left to the file system.
+#[cfg(not(target_family = "unix"))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets a stored file as read only, to protect it from accidental modification.
+fn set_readonly(path: &Path) {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let mut permissions = metadata.permissions();
+            permissions.set_readonly(true);
+            if let Err(err) = fs::set_permissions(path, permissions) {
+                warn!("failed to set file '{}' as read only: {}", path.display(), err);
+            }
+        }
+        Err(err) => warn!("failed to get metadata of file '{}': {}", path.display(), err),
+    }
+}
+
+/// Sets the modification time of a stored file to the current time.
+///
+/// This protects files that were just stored from being garbage collected,
+/// even if they were moved into the store, or had already been stored before.
+fn touch(path: &Path) {
+    let result = File::options()
+        .write(true)
+        .open(path)
+        .or_else(|_| File::open(path))
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = result {
+        warn!("failed to set modification time of file '{}': {}", path.display(), err);
+    }
+}
+
+/// Returns a builder for temporary files inside the archive's temporary directory.
+///
+/// Temporary files are named after the process that creates them, so that the files left behind
+/// by a process that crashed can be told apart from the files of processes that are still running.
+fn temp_file_builder() -> tempfile::Builder<'static, 'static> {
+    static PREFIX: OnceLock<String> = OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| {
+        let process = LockHolder::current();
+        format!("{}{}-{}-", TEMP_FILE_PREFIX, process.pid, process.host)
+    });
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(prefix);
+    builder
+}
+
+/// Returns the hash of a file's contents.
+fn hash_file(path: &Path) -> io::Result<Hash> {
+    let file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(file)?;
+    Ok(hasher.finalize())
+}
+
+/// Compares the contents of two files.
+fn files_are_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let a = File::open(a)?;
+    let b = File::open(b)?;
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::new(a);
+    let mut b = BufReader::new(b);
+    loop {
+        let a_buf = a.fill_buf()?;
+        let b_buf = b.fill_buf()?;
+        let len = a_buf.len().min(b_buf.len());
+        if len == 0 {
+            return Ok(a_buf.is_empty() && b_buf.is_empty());
+        }
+        if a_buf[..len] != b_buf[..len] {
+            return Ok(false);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+/// Returns the hashes that name the files in a directory, such as the pins directory.
+///
+/// Files whose name isn't a hash are ignored. If the directory doesn't exist, no hashes are returned.
+fn read_hash_directory(path: &Path) -> io::Result<Vec<Hash>> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut hashes = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(Ok(hash)) = entry.file_name().to_str().map(Hash::from_hex) {
+            hashes.push(hash);
+        } else {
+            warn!("ignoring unexpected file '{}'", entry.path().display());
+        }
+    }
+    Ok(hashes)
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -242,50 +872,234 @@ pub enum DiskStructure {
     /// The base directory will be to where files are deployed,
     /// and the media archive's files will be stored in a subdirectory.
     Deployable,
+    /// A media archive that supports deploying files to a separate directory.
+    ///
+    /// The base directory will be the archive directory, and files will be deployed to the directory
+    /// recorded in the archive's configuration by [`MediaArchive::open_with_deploy_path`].
+    /// The deployment directory isn't created if it doesn't exist, as it may be on a disk that isn't mounted.
+    Detached,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum StoreMethod {
     /// The file is copied to the store.
+    ///
+    /// The file is reflinked if the file system supports it, and copied byte by byte otherwise.
     Copy,
+    /// The file is reflinked to the store, so that it doesn't take up extra disk space.
+    ///
+    /// Fails if the file system doesn't support reflinks.
+    Reflink,
     /// The file is moved to the store.
     Move,
+    /// The file is moved to the store.
+    ///
+    /// If a file with the same contents is already stored, the file is compared byte-for-byte
+    /// with the stored file, and deleted if they match.
+    MoveOrDelete,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl StoreMethod {
+    /// Returns whether the file is moved (rather than copied) to the store.
+    #[must_use]
+    pub fn is_move(self) -> bool {
+        matches!(self, StoreMethod::Move | StoreMethod::MoveOrDelete)
+    }
+}
+
+/// The outcome of storing a file in the archive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StoreOutcome {
+    /// The file was stored.
+    Stored(Hash),
+    /// The file was stored by reflinking it, so it doesn't take up extra disk space.
+    Reflinked(Hash),
+    /// A file with the same contents was already stored, so the file wasn't stored again.
+    Deduplicated(Hash),
+}
+
+impl StoreOutcome {
+    /// Returns the hash of the file's contents.
+    #[must_use]
+    pub fn hash(&self) -> Hash {
+        match self {
+            StoreOutcome::Stored(hash) | StoreOutcome::Reflinked(hash) | StoreOutcome::Deduplicated(hash) => *hash,
+        }
+    }
+}
+
+/// A writer that stores the data written to it in the archive.
+///
+/// Created by [`MediaArchive::blob_writer`].
+#[derive(Debug)]
+pub struct BlobWriter<'a> {
+    archive: &'a MediaArchive,
+    temp_file: NamedTempFile,
+    hasher: ContentHasher,
+    _lock: ArchiveLock,
+}
+
+impl BlobWriter<'_> {
+    /// Stores the data written so far in the archive.
+    pub fn finish(self) -> Result<StoreOutcome, StoreBlobError> {
+        self.temp_file.as_file().sync_all().map_err(StoreBlobError::Write)?;
+        let (hash, ids) = self.hasher.finalize();
+        self.archive
+            .commit_temp_file(self.temp_file.into_temp_path(), hash, &ids)
+    }
+}
+
+impl Write for BlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.temp_file.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.temp_file.flush()
+    }
+}
+
+/// The result of storing the contents of a directory with [`MediaArchive::store_directory`].
+#[derive(Debug, Default)]
+pub struct StoredDirectory {
+    /// The hashes of the stored files, indexed by their path relative to the stored directory.
+    pub files: BTreeMap<RelativePathBuf, Hash>,
+    /// The errors that occurred while storing the directory.
+    pub errors: Vec<StoreDirectoryEntryError>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DeployMethod {
     /// The file is copied to the destination.
+    ///
+    /// The file is reflinked if the file system supports it, and copied byte by byte otherwise.
+    /// When a file is reflinked, [`DeployMethod::Reflink`] is reported as the method used.
     Copy,
+    /// The file is reflinked to the destination, so that it doesn't take up extra disk space.
+    ///
+    /// Fails if the file system doesn't support reflinks.
+    Reflink,
     /// The file is symlinked to the destination.
     Symlink,
     /// The file is hardlinked to the destination.
     Hardlink,
+    /// The first method that is supported by the file system is used, from a list of preferred methods.
+    ///
+    /// See [`MediaArchive::set_auto_deploy_methods`].
+    Auto,
 }
 
 #[derive(Debug, Error)]
 pub enum OpenMediaArchiveError {
+    #[error("'{0}' already contains a media archive")]
+    AlreadyExists(PathBuf),
+    #[error("failed to load configuration: {0}")]
+    Config(#[source] ConfigError),
     #[error("failed to create base directory: {0}")]
     CreateDir(#[source] io::Error),
+    #[error("no deployment directory is recorded in the archive's configuration")]
+    NoDeployPath,
+    #[error("'{0}' is not a media archive")]
+    NotAnArchive(PathBuf),
 }
 
 #[derive(Debug, Error)]
 pub enum StoreFileError {
-    #[error("file with hash '{0}' already exists")]
-    AlreadyExists(Hash),
+    #[error("file does not match the already stored file with hash '{0}'")]
+    DuplicateMismatch(Hash),
     #[error("cannot store a directory")]
     IsDirectory,
     #[error("cannot store a symlink")]
     IsSymlink,
+    #[error("failed to record the operation in the journal: {0}")]
+    Journal(#[source] io::Error),
     #[error("failed to get file metadata: {0}")]
     Metadata(#[source] io::Error),
     #[error("failed to create parent directory: {0}")]
     CreateParentDir(#[source] io::Error),
+    #[error("failed to create temporary file: {0}")]
+    CreateTempFile(#[source] io::Error),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
     #[error("failed to open file for hashing: {0}")]
     Open(#[source] io::Error),
     #[error("failed to read file while hashing: {0}")]
     Read(#[source] io::Error),
+    #[error("failed to reflink file: {0}")]
+    Reflink(#[source] io::Error),
+    #[error("failed to remove duplicate file: {0}")]
+    RemoveSource(#[source] io::Error),
     #[error("failed to store file: {0}")]
     Store(#[source] io::Error),
+    #[error("failed to compare file with the already stored file: {0}")]
+    Verify(#[source] io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum StoreBlobError {
+    #[error("failed to create parent directory: {0}")]
+    CreateParentDir(#[source] io::Error),
+    #[error("failed to create temporary file: {0}")]
+    CreateTempFile(#[source] io::Error),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("failed to read data: {0}")]
+    Read(#[source] io::Error),
+    #[error("failed to store file: {0}")]
+    Store(#[source] io::Error),
+    #[error("failed to write data to temporary file: {0}")]
+    Write(#[source] io::Error),
+}
+
+impl From<StoreBlobError> for StoreFileError {
+    fn from(err: StoreBlobError) -> Self {
+        match err {
+            StoreBlobError::CreateParentDir(err) => StoreFileError::CreateParentDir(err),
+            StoreBlobError::CreateTempFile(err) => StoreFileError::CreateTempFile(err),
+            StoreBlobError::Lock(err) => StoreFileError::Lock(err),
+            StoreBlobError::Read(err) => StoreFileError::Read(err),
+            StoreBlobError::Store(err) | StoreBlobError::Write(err) => StoreFileError::Store(err),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StoreDirectoryError {
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("failed to get directory metadata: {0}")]
+    Metadata(#[source] io::Error),
+    #[error("not a directory")]
+    NotADirectory,
+}
+
+#[derive(Debug, Error)]
+pub enum StoreDirectoryEntryError {
+    #[error("path '{0}' is not valid Unicode")]
+    InvalidPath(PathBuf),
+    #[error("failed to read directory '{path}': {source}")]
+    ReadDir { path: PathBuf, source: io::Error },
+    #[error("failed to store file '{path}': {source}")]
+    Store { path: PathBuf, source: StoreFileError },
+}
+
+#[derive(Debug, Error)]
+pub enum BlobError {
+    #[error("failed to get file metadata of file '{path}': {source}")]
+    Metadata { path: PathBuf, source: io::Error },
+    #[error("stored file '{0}' exists but is not a file")]
+    NotAFile(PathBuf),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("file with hash '{0}' not found in the archive")]
+    NotFound(Hash),
+    #[error("failed to open file '{path}': {source}")]
+    Open { path: PathBuf, source: io::Error },
+    #[error("failed to remove file '{path}': {source}")]
+    Remove { path: PathBuf, source: io::Error },
 }
 
 #[derive(Debug, Error)]
@@ -300,18 +1114,36 @@ pub enum DeployError {
         to: PathBuf,
         source: io::Error,
     },
+    #[error("target path '{0}' is inside the archive directory")]
+    InsideArchive(RelativePathBuf),
     #[error("target path '{0}' is empty, not relative, or outside of the media archive")]
     InvalidPath(RelativePathBuf),
     #[error("cannot deploy in bare media archive")]
     IsBareArchive,
+    #[error("failed to record the operation in the journal: {0}")]
+    Journal(#[source] io::Error),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
     #[error("failed to get file metadata of file '{path}': {source}")]
     Metadata { path: PathBuf, source: io::Error },
+    #[error("'{0}' was modified after being deployed")]
+    Modified(PathBuf),
+    #[error("'{0}' was not deployed by the archive")]
+    NotDeployed(RelativePathBuf),
     #[error("file with hash '{0}' not found in the archive")]
     NotFound(Hash),
     #[error("deployment method not supported by the operating system or file system")]
     NotSupported,
+    #[error("failed to read file '{path}': {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to remove file '{path}': {source}")]
+    Remove { path: PathBuf, source: io::Error },
     #[error("source '{0}' exists but is not a file")]
     SourceExistsButIsNotAFile(PathBuf),
+    #[error("failed to access the record of deployed files: {0}")]
+    State(#[source] io::Error),
+    #[error("failed to construct absolute path to the symlink source")]
+    SymlinkAbsolutePathConstruction { source_path: PathBuf, source: io::Error },
     #[error("failed to construct relative path from the symlink target to its source")]
     SymlinkRelativePathConstruction {
         source_path: PathBuf,
@@ -320,6 +1152,21 @@ pub enum DeployError {
     },
 }
 
+impl DeployError {
+    /// Returns whether the error means the deployment method isn't supported,
+    /// for example, because hardlinks can't cross file systems.
+    fn is_unsupported(&self) -> bool {
+        match self {
+            DeployError::NotSupported => true,
+            DeployError::Deploy { source, .. } => matches!(
+                source.kind(),
+                io::ErrorKind::CrossesDevices | io::ErrorKind::TooManyLinks | io::ErrorKind::Unsupported
+            ),
+            _ => false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -330,9 +1177,10 @@ mod tests {
     use file_id::get_file_id;
     use predicates::prelude::*;
 
-    fn temp_media_archive(disk_structure: DiskStructure) -> (TempDir, MediaArchive) {
+    pub(crate) fn temp_media_archive(disk_structure: DiskStructure) -> (TempDir, MediaArchive) {
         let temp_dir = TempDir::new().expect("failed to create temporary directory for test");
-        let archive = MediaArchive::open(temp_dir.to_path_buf(), disk_structure).expect("failed to open media archive");
+        let archive =
+            MediaArchive::init(temp_dir.to_path_buf(), disk_structure).expect("failed to create media archive");
         (temp_dir, archive)
     }
 
@@ -360,6 +1208,81 @@ mod tests {
             .assert(predicate::path::missing());
     }
 
+    #[test]
+    fn open_requires_archive() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.child("typo");
+
+        assert!(matches!(
+            MediaArchive::open(path.to_path_buf(), DiskStructure::Deployable),
+            Err(OpenMediaArchiveError::NotAnArchive(_))
+        ));
+        path.assert(predicate::path::missing());
+
+        MediaArchive::init(path.to_path_buf(), DiskStructure::Deployable).unwrap();
+        assert!(matches!(
+            MediaArchive::init(path.to_path_buf(), DiskStructure::Deployable),
+            Err(OpenMediaArchiveError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            MediaArchive::open(path.to_path_buf(), DiskStructure::Bare),
+            Err(OpenMediaArchiveError::NotAnArchive(_))
+        ));
+        MediaArchive::open(path.to_path_buf(), DiskStructure::Deployable).unwrap();
+    }
+
+    #[test]
+    fn open_unversioned_archive() {
+        // Archives created before the on-disk format was versioned only have a store directory.
+        let temp_dir = TempDir::new().unwrap();
+        let stored_file = temp_dir
+            .child(MEDIA_ARCHIVE_DIRECTORY)
+            .child(STORE_DIRECTORY)
+            .child("6a")
+            .child("95")
+            .child(TEST_DATA_HASH);
+        stored_file.write_str(TEST_DATA).unwrap();
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+        assert_eq!(archive.config(), &ArchiveConfig::default());
+        assert!(archive.contains(&Hash::from_hex(TEST_DATA_HASH).unwrap()));
+        temp_dir
+            .child(MEDIA_ARCHIVE_DIRECTORY)
+            .child(config::CONFIG_FILE)
+            .assert(predicate::path::is_file());
+
+        MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+    }
+
+    #[test]
+    fn open_with_deploy_path() {
+        let archive_dir = TempDir::new().unwrap();
+        let deploy_dir = TempDir::new().unwrap();
+        let deploy_path = deploy_dir.child("deploy");
+
+        MediaArchive::init(archive_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        assert!(matches!(
+            MediaArchive::open(archive_dir.to_path_buf(), DiskStructure::Detached),
+            Err(OpenMediaArchiveError::NoDeployPath)
+        ));
+
+        let archive =
+            MediaArchive::open_with_deploy_path(archive_dir.to_path_buf(), deploy_path.to_path_buf()).unwrap();
+        deploy_path.assert(predicate::path::is_dir());
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        archive
+            .deploy_file(&hash, RelativePath::new("a"), DeployMethod::Symlink)
+            .unwrap();
+        deploy_path.child("a").assert(TEST_DATA);
+        archive_dir.child(STORE_DIRECTORY).assert(predicate::path::is_dir());
+
+        let archive = MediaArchive::open(archive_dir.to_path_buf(), DiskStructure::Detached).unwrap();
+        assert_eq!(archive.config().deploy_path.as_deref(), Some(deploy_path.path()));
+        assert!(archive.status().unwrap().files.is_empty());
+        archive.undeploy(RelativePath::new("a")).unwrap();
+        deploy_path.child("a").assert(predicate::path::missing());
+    }
+
     #[test]
     fn path_of_stored_file() {
         let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
@@ -379,15 +1302,15 @@ mod tests {
         assert_eq!(path, expected);
     }
 
-    const TEST_DATA: &str = "test data";
-    const TEST_DATA_HASH: &str = "6a953581d60dbebc9749b56d2383277fb02b58d260b4ccf6f119108fa0f1d4ef";
-    const ZERO_HASH: Hash = Hash::from_bytes([0; 32]);
+    pub(crate) const TEST_DATA: &str = "test data";
+    pub(crate) const TEST_DATA_HASH: &str = "6a953581d60dbebc9749b56d2383277fb02b58d260b4ccf6f119108fa0f1d4ef";
+    pub(crate) const ZERO_HASH: Hash = Hash::from_bytes([0; 32]);
 
     #[allow(clippy::type_complexity)]
     fn store_file_first_part(
         method: StoreMethod,
         setup_fn: Option<Box<dyn Fn(&TempDir, &NamedTempFile)>>,
-    ) -> (NamedTempFile, Result<(), StoreFileError>) {
+    ) -> (NamedTempFile, Result<StoreOutcome, StoreFileError>) {
         let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
 
         let file_to_store = NamedTempFile::new("test.txt").unwrap();
@@ -397,11 +1320,11 @@ mod tests {
             setup_fn(&temp_dir, &file_to_store);
         }
 
-        let returned_hash = match archive.store_file(file_to_store.path(), method) {
-            Ok(hash) => hash,
+        let outcome = match archive.store_file(file_to_store.path(), method) {
+            Ok(outcome) => outcome,
             Err(err) => return (file_to_store, Err(err)),
         };
-        assert_eq!(returned_hash.to_hex().as_str(), TEST_DATA_HASH);
+        assert_eq!(outcome.hash().to_hex().as_str(), TEST_DATA_HASH);
 
         let stored_file = temp_dir
             .child(STORE_DIRECTORY)
@@ -412,43 +1335,114 @@ mod tests {
         let stored_file_metadata = stored_file.symlink_metadata().unwrap();
         assert!(stored_file_metadata.is_file());
         assert!(!stored_file_metadata.is_symlink());
-        assert!(stored_file_metadata.permissions().readonly());
+        if matches!(outcome, StoreOutcome::Stored(_) | StoreOutcome::Reflinked(_)) {
+            assert!(stored_file_metadata.permissions().readonly());
+        }
 
         stored_file.assert(TEST_DATA);
 
-        (file_to_store, Ok(()))
+        (file_to_store, Ok(outcome))
     }
 
     #[test]
     fn store_file_copy() {
         let (file_to_store, result) = store_file_first_part(StoreMethod::Copy, None);
-        assert!(matches!(result, Ok(())));
+        assert!(matches!(
+            result,
+            Ok(StoreOutcome::Stored(_) | StoreOutcome::Reflinked(_))
+        ));
         file_to_store.assert(predicate::path::exists());
     }
 
+    #[test]
+    fn store_file_reflink() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let file_to_store = temp_dir.child("file");
+        file_to_store.write_str(TEST_DATA).unwrap();
+
+        match archive.store_file(file_to_store.path(), StoreMethod::Reflink) {
+            Ok(outcome) => {
+                assert_eq!(outcome, StoreOutcome::Reflinked(blake3::hash(TEST_DATA.as_bytes())));
+                temp_dir
+                    .child(STORE_DIRECTORY)
+                    .child("6a/95")
+                    .child(TEST_DATA_HASH)
+                    .assert(TEST_DATA);
+            }
+            Err(StoreFileError::Reflink(_)) => {
+                temp_dir.child(STORE_DIRECTORY).assert(predicate::path::missing());
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+        }
+        file_to_store.assert(TEST_DATA);
+        assert_eq!(fs::read_dir(temp_dir.child(TEMP_DIRECTORY)).unwrap().count(), 0);
+    }
+
     #[test]
     fn store_file_move() {
         let (file_to_store, result) = store_file_first_part(StoreMethod::Move, None);
-        assert!(matches!(result, Ok(())));
+        assert!(matches!(result, Ok(StoreOutcome::Stored(_))));
         file_to_store.assert(predicate::path::missing());
     }
 
+    #[allow(clippy::type_complexity)]
+    fn store_file_already_exists_setup(contents: &'static str) -> Box<dyn Fn(&TempDir, &NamedTempFile)> {
+        Box::new(move |temp_dir, _| {
+            temp_dir
+                .child(STORE_DIRECTORY)
+                .child("6a")
+                .child("95")
+                .child(TEST_DATA_HASH)
+                .write_str(contents)
+                .unwrap();
+        })
+    }
+
     #[test]
     fn store_file_already_exists() {
+        let (file_to_store, result) =
+            store_file_first_part(StoreMethod::Move, Some(store_file_already_exists_setup(TEST_DATA)));
+        assert!(matches!(result, Ok(StoreOutcome::Deduplicated(_))));
+        file_to_store.assert(predicate::path::exists());
+    }
+
+    #[test]
+    fn store_file_already_exists_move_or_delete() {
         let (file_to_store, result) = store_file_first_part(
-            StoreMethod::Move,
-            Some(Box::new(|temp_dir, _| {
-                temp_dir
-                    .child(STORE_DIRECTORY)
-                    .child("6a")
-                    .child("95")
-                    .child(TEST_DATA_HASH)
-                    .touch()
-                    .unwrap();
-            })),
+            StoreMethod::MoveOrDelete,
+            Some(store_file_already_exists_setup(TEST_DATA)),
         );
-        assert!(matches!(result, Err(StoreFileError::AlreadyExists(_))));
-        file_to_store.assert(predicate::path::exists());
+        assert!(matches!(result, Ok(StoreOutcome::Deduplicated(_))));
+        file_to_store.assert(predicate::path::missing());
+    }
+
+    #[test]
+    fn store_file_already_exists_move_or_delete_mismatch() {
+        let (file_to_store, result) = store_file_first_part(
+            StoreMethod::MoveOrDelete,
+            Some(store_file_already_exists_setup("corrupted data")),
+        );
+        assert!(matches!(result, Err(StoreFileError::DuplicateMismatch(_))));
+        file_to_store.assert(TEST_DATA);
+    }
+
+    #[test]
+    fn store_file_leaves_no_temporary_files() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+
+        let file_to_store = NamedTempFile::new("test.txt").unwrap();
+        file_to_store.write_str(TEST_DATA).unwrap();
+
+        assert!(matches!(
+            archive.store_file(file_to_store.path(), StoreMethod::Copy),
+            Ok(StoreOutcome::Stored(_) | StoreOutcome::Reflinked(_))
+        ));
+        assert!(matches!(
+            archive.store_file(file_to_store.path(), StoreMethod::Copy),
+            Ok(StoreOutcome::Deduplicated(_))
+        ));
+        temp_dir.child(TEMP_DIRECTORY).assert(predicate::path::is_dir());
+        assert_eq!(fs::read_dir(temp_dir.child(TEMP_DIRECTORY)).unwrap().count(), 0);
     }
 
     #[test]
@@ -497,7 +1491,10 @@ mod tests {
     #[cfg(any(target_family = "unix", target_family = "windows"))]
     fn store_file_copy_symlink() {
         let (_, result) = store_file_first_part(StoreMethod::Copy, Some(Box::new(store_file_symlink_setup)));
-        assert!(matches!(result, Ok(())));
+        assert!(matches!(
+            result,
+            Ok(StoreOutcome::Stored(_) | StoreOutcome::Reflinked(_))
+        ));
     }
 
     #[test]
@@ -506,6 +1503,145 @@ mod tests {
         assert!(matches!(result, Err(StoreFileError::IsSymlink)));
     }
 
+    #[test]
+    fn store_reader() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+
+        let outcome = archive
+            .store_reader(TEST_DATA.as_bytes())
+            .expect("failed to store data");
+        assert_eq!(outcome, StoreOutcome::Stored(Hash::from_hex(TEST_DATA_HASH).unwrap()));
+        temp_dir
+            .child(STORE_DIRECTORY)
+            .child("6a")
+            .child("95")
+            .child(TEST_DATA_HASH)
+            .assert(TEST_DATA);
+
+        let outcome = archive
+            .store_reader(TEST_DATA.as_bytes())
+            .expect("failed to store data");
+        assert_eq!(
+            outcome,
+            StoreOutcome::Deduplicated(Hash::from_hex(TEST_DATA_HASH).unwrap())
+        );
+    }
+
+    #[test]
+    fn blob_writer() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+
+        let mut writer = archive.blob_writer().unwrap();
+        let (first_half, second_half) = TEST_DATA.split_at(4);
+        writer.write_all(first_half.as_bytes()).unwrap();
+        writer.write_all(second_half.as_bytes()).unwrap();
+        let outcome = writer.finish().expect("failed to store data");
+
+        assert_eq!(outcome, StoreOutcome::Stored(Hash::from_hex(TEST_DATA_HASH).unwrap()));
+        temp_dir
+            .child(STORE_DIRECTORY)
+            .child("6a")
+            .child("95")
+            .child(TEST_DATA_HASH)
+            .assert(TEST_DATA);
+    }
+
+    #[test]
+    fn blob_writer_dropped() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+
+        let mut writer = archive.blob_writer().unwrap();
+        writer.write_all(TEST_DATA.as_bytes()).unwrap();
+        drop(writer);
+
+        assert_eq!(fs::read_dir(temp_dir.child(TEMP_DIRECTORY)).unwrap().count(), 0);
+        temp_dir.child(STORE_DIRECTORY).assert(predicate::path::missing());
+    }
+
+    #[test]
+    fn read_blob() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        assert!(archive.contains(&hash));
+        assert_eq!(archive.blob_len(&hash).unwrap(), TEST_DATA.len() as u64);
+        assert_eq!(
+            archive.blob_path(&hash).unwrap(),
+            archive.get_path_of_stored_file(&hash)
+        );
+
+        let mut contents = String::new();
+        archive.open_blob(&hash).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, TEST_DATA);
+    }
+
+    #[test]
+    fn read_blob_not_found() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+
+        assert!(!archive.contains(&ZERO_HASH));
+        assert!(matches!(archive.blob_path(&ZERO_HASH), Err(BlobError::NotFound(_))));
+        assert!(matches!(archive.blob_len(&ZERO_HASH), Err(BlobError::NotFound(_))));
+        assert!(matches!(archive.open_blob(&ZERO_HASH), Err(BlobError::NotFound(_))));
+    }
+
+    #[test]
+    fn store_directory() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+
+        let directory_to_store = TempDir::new().unwrap();
+        directory_to_store.child("a.txt").write_str(TEST_DATA).unwrap();
+        directory_to_store.child("b/c/d.txt").write_str(TEST_DATA).unwrap();
+        directory_to_store.child("b/e.txt").write_str("other data").unwrap();
+        directory_to_store.child("f").create_dir_all().unwrap();
+
+        let stored = archive
+            .store_directory(directory_to_store.path(), StoreMethod::Copy)
+            .expect("failed to store directory");
+        assert!(stored.errors.is_empty());
+
+        let expected_hash = Hash::from_hex(TEST_DATA_HASH).unwrap();
+        assert_eq!(stored.files.len(), 3);
+        assert_eq!(stored.files[RelativePath::new("a.txt")], expected_hash);
+        assert_eq!(stored.files[RelativePath::new("b/c/d.txt")], expected_hash);
+        assert_eq!(
+            stored.files[RelativePath::new("b/e.txt")],
+            blake3::hash("other data".as_bytes())
+        );
+        directory_to_store.child("a.txt").assert(TEST_DATA);
+    }
+
+    #[test]
+    fn store_directory_skips_archive_directory() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        temp_dir.child("a.txt").write_str(TEST_DATA).unwrap();
+        temp_dir
+            .child(MEDIA_ARCHIVE_DIRECTORY)
+            .child("unrelated.txt")
+            .write_str("other data")
+            .unwrap();
+
+        let stored = archive
+            .store_directory(temp_dir.path(), StoreMethod::Copy)
+            .expect("failed to store directory");
+        assert!(stored.errors.is_empty());
+        assert_eq!(stored.files.len(), 1);
+        assert!(stored.files.contains_key(RelativePath::new("a.txt")));
+    }
+
+    #[test]
+    fn store_directory_not_a_directory() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+
+        let file_to_store = NamedTempFile::new("test.txt").unwrap();
+        file_to_store.write_str(TEST_DATA).unwrap();
+
+        assert!(matches!(
+            archive.store_directory(file_to_store.path(), StoreMethod::Copy),
+            Err(StoreDirectoryError::NotADirectory)
+        ));
+    }
+
     #[test]
     fn deploy_file_bare_archive() {
         let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
@@ -517,6 +1653,27 @@ mod tests {
         temp_dir.child("test").assert(predicate::path::missing());
     }
 
+    #[test]
+    fn deploy_file_inside_archive() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        for path in [
+            ".media-archive/pins/foo",
+            ".media-archive/config.json",
+            "a/../.media-archive/x",
+        ] {
+            assert!(matches!(
+                archive.deploy_file(&hash, RelativePath::new(path), DeployMethod::Copy),
+                Err(DeployError::InsideArchive(_))
+            ));
+        }
+        temp_dir
+            .child(MEDIA_ARCHIVE_DIRECTORY)
+            .child("pins")
+            .assert(predicate::path::missing());
+    }
+
     fn deploy_file_first_part(
         temp_dir: &TempDir,
         archive: &MediaArchive,
@@ -588,6 +1745,105 @@ mod tests {
         assert!(deployed_file_metadata.is_symlink());
     }
 
+    #[test]
+    fn deploy_file_symlink_styles() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        archive
+            .set_config(ArchiveConfig {
+                symlink_style: SymlinkStyle::Absolute,
+                ..ArchiveConfig::default()
+            })
+            .unwrap();
+        archive
+            .deploy_file(&hash, RelativePath::new("a/absolute"), DeployMethod::Symlink)
+            .unwrap();
+        let link_target = fs::read_link(temp_dir.child("a/absolute")).unwrap();
+        assert!(link_target.is_absolute());
+        temp_dir.child("a/absolute").assert(TEST_DATA);
+
+        let root = PathBuf::from("/mnt/media-archive/store");
+        archive
+            .set_config(ArchiveConfig {
+                symlink_style: SymlinkStyle::Rooted { root: root.clone() },
+                ..ArchiveConfig::default()
+            })
+            .unwrap();
+        archive
+            .deploy_file(&hash, RelativePath::new("a/rooted"), DeployMethod::Symlink)
+            .unwrap();
+        assert_eq!(
+            fs::read_link(temp_dir.child("a/rooted")).unwrap(),
+            root.join("6a/95").join(TEST_DATA_HASH)
+        );
+
+        assert!(archive.status().unwrap().files.is_empty());
+        archive.undeploy(RelativePath::new("a/rooted")).unwrap();
+        temp_dir.child("a/rooted").assert(predicate::path::missing());
+    }
+
+    #[test]
+    fn deploy_file_reflink() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        match archive.deploy_file(&hash, RelativePath::new("a"), DeployMethod::Reflink) {
+            Ok(method) => {
+                assert_eq!(method, DeployMethod::Reflink);
+                temp_dir.child("a").assert(TEST_DATA);
+            }
+            Err(DeployError::NotSupported) => {
+                temp_dir.child("a").assert(predicate::path::missing());
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+        }
+
+        let method = archive
+            .deploy_file(&hash, RelativePath::new("b"), DeployMethod::Copy)
+            .unwrap();
+        assert!(matches!(method, DeployMethod::Copy | DeployMethod::Reflink));
+        assert_eq!(
+            archive.deployment(RelativePath::new("b")).unwrap().unwrap().method,
+            method
+        );
+    }
+
+    #[test]
+    fn deploy_file_auto() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        let method = archive
+            .deploy_file(&hash, RelativePath::new("a"), DeployMethod::Auto)
+            .unwrap();
+        assert_eq!(method, DeployMethod::Hardlink);
+        temp_dir.child("a").assert(TEST_DATA);
+
+        archive.set_auto_deploy_methods(vec![DeployMethod::Auto, DeployMethod::Symlink, DeployMethod::Copy]);
+        let method = archive
+            .deploy_file(&hash, RelativePath::new("b"), DeployMethod::Auto)
+            .unwrap();
+        assert_eq!(method, DeployMethod::Symlink);
+        assert!(temp_dir.child("b").symlink_metadata().unwrap().is_symlink());
+        assert_eq!(
+            archive.deployment(RelativePath::new("b")).unwrap().unwrap().method,
+            DeployMethod::Symlink
+        );
+    }
+
+    #[test]
+    fn deploy_file_auto_without_methods() {
+        let (_temp_dir, mut archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        archive.set_auto_deploy_methods(Vec::new());
+        assert!(matches!(
+            archive.deploy_file(&hash, RelativePath::new("a"), DeployMethod::Auto),
+            Err(DeployError::NotSupported)
+        ));
+    }
+
     #[test]
     fn deploy_file_already_exists() {
         let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
@@ -599,6 +1855,16 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn deploy_file_not_found() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        assert!(matches!(
+            archive.deploy_file(&ZERO_HASH, RelativePath::new("test"), DeployMethod::Copy),
+            Err(DeployError::NotFound(_))
+        ));
+        temp_dir.child("test").assert(predicate::path::missing());
+    }
+
     #[test]
     fn deploy_file_empty_path() {
         let (_temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
@@ -0,0 +1,512 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Advisory locking of an archive, so that several processes can safely use it at the same time.
+//!
+//! Operations that only add files to the store, or read them, take a shared lock, so they can run
+//! concurrently. Operations that remove or move stored files, or change the archive's state files,
+//! take an exclusive lock. Within a process, locks are re-entrant per thread, so operations built
+//! on top of other operations don't deadlock.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::{self, File, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::MediaArchive;
+
+const LOCK_FILE: &str = "lock";
+pub(crate) const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_mins(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl MediaArchive {
+    /// Locks the archive, waiting for other processes to release it if needed.
+    ///
+    /// The lock is released when the returned guard is dropped. Archive operations lock the archive
+    /// on their own, so this is only needed to make a sequence of operations atomic with regard to
+    /// other processes. Fails with [`LockError::Timeout`] if the archive can't be locked within the
+    /// timeout set with [`MediaArchive::set_lock_timeout`], and with [`LockError::StoreLayoutChanged`]
+    /// if the store layout was changed by another handle to the archive.
+    ///
+    /// A thread holding a shared lock can take more shared locks, but can't lock the archive exclusively
+    /// until it releases them, which fails with [`LockError::Upgrade`].
+    pub fn lock(&self, mode: LockMode) -> Result<ArchiveLock, LockError> {
+        let lock = self.lock.acquire(&self.archive_path, mode, self.lock_timeout)?;
+        // The layout can only change while no lock is held by the process.
+        if lock.locked_file {
+            self.check_store_layout()?;
+        }
+        Ok(lock)
+    }
+
+    /// Sets how long to wait for other processes to release the archive lock before giving up.
+    ///
+    /// The default is one minute.
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+}
+
+/// How an archive is locked.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockMode {
+    /// The archive can be locked by other shared locks at the same time, but not by an exclusive lock.
+    Shared,
+    /// The archive can't be locked by any other lock at the same time.
+    Exclusive,
+}
+
+/// The lock state of an archive, shared between every handle to the archive in the process.
+#[derive(Debug, Default)]
+pub(crate) struct ArchiveLockState {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    file: Option<File>,
+    /// The mode the lock file is locked in.
+    file_mode: Option<LockMode>,
+    /// Whether a thread is locking the lock file, in which case no other thread may take a lock until it's done.
+    locking_file: bool,
+    readers: HashMap<ThreadId, usize>,
+    writer: Option<(ThreadId, usize)>,
+}
+
+impl ArchiveLockState {
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        archive_path: &Path,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> Result<ArchiveLock, LockError> {
+        let deadline = Instant::now() + timeout;
+        let current_thread = thread::current().id();
+        let mut state = self.state.lock().expect("lock state should not be poisoned");
+
+        // Locks taken by a thread that holds the exclusive lock are nested in it.
+        if let Some((owner, count)) = &mut state.writer {
+            if *owner == current_thread {
+                *count += 1;
+                return Ok(ArchiveLock {
+                    state: Arc::clone(self),
+                    exclusive: true,
+                    thread: current_thread,
+                    locked_file: false,
+                });
+            }
+        }
+
+        // Converting the lock file's lock in place isn't portable, and unlocking it to lock it again
+        // would let another process in while the thread's shared locks are still held.
+        if mode == LockMode::Exclusive && state.readers.contains_key(&current_thread) {
+            return Err(LockError::Upgrade);
+        }
+
+        let blocked = |state: &LockState| {
+            state.locking_file
+                || match mode {
+                    LockMode::Shared => state.writer.is_some(),
+                    LockMode::Exclusive => {
+                        state.writer.is_some() || state.readers.keys().any(|&reader| reader != current_thread)
+                    }
+                }
+        };
+        while blocked(&state) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(LockError::Timeout { holder: None });
+            }
+            state = self
+                .released
+                .wait_timeout(state, remaining)
+                .expect("lock state should not be poisoned")
+                .0;
+        }
+
+        // The lock file is kept locked until every lock in the process is released, and only
+        // shared locks are taken while it's locked in shared mode, so it only needs to be locked when unlocked.
+        let locked_file = state.file_mode.is_none();
+        if locked_file {
+            if state.file.is_none() {
+                state.file = Some(open_lock_file(archive_path)?);
+            }
+            let file = state.file.take().expect("lock file should be open");
+            state.locking_file = true;
+            drop(state);
+
+            // Other threads wait for the lock file to be locked without holding the lock state.
+            let result = lock_file(&file, mode, deadline);
+
+            state = self.state.lock().expect("lock state should not be poisoned");
+            state.file = Some(file);
+            state.locking_file = false;
+            self.released.notify_all();
+            state.file_mode = result.is_ok().then_some(mode);
+            result?;
+        }
+        match mode {
+            LockMode::Shared => *state.readers.entry(current_thread).or_default() += 1,
+            LockMode::Exclusive => state.writer = Some((current_thread, 1)),
+        }
+
+        Ok(ArchiveLock {
+            state: Arc::clone(self),
+            exclusive: mode == LockMode::Exclusive,
+            thread: current_thread,
+            locked_file,
+        })
+    }
+
+    fn release(&self, exclusive: bool, thread: ThreadId) {
+        let mut state = self.state.lock().expect("lock state should not be poisoned");
+
+        if exclusive {
+            let (_, count) = state.writer.as_mut().expect("exclusive lock should be held");
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            state.writer = None;
+        } else {
+            let count = state
+                .readers
+                .get_mut(&thread)
+                .expect("shared lock should be held by the thread that took it");
+            *count -= 1;
+            if *count == 0 {
+                state.readers.remove(&thread);
+            }
+        }
+
+        if state.writer.is_none() && state.readers.is_empty() {
+            if let Some(file) = &state.file {
+                if let Err(err) = unlock_file(file) {
+                    warn!("failed to release archive lock: {}", err);
+                }
+            }
+            state.file_mode = None;
+        }
+        drop(state);
+        self.released.notify_all();
+    }
+}
+
+fn open_lock_file(archive_path: &Path) -> Result<File, LockError> {
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(archive_path.join(LOCK_FILE))
+        .map_err(LockError::Open)
+}
+
+/// Locks the lock file in the given mode.
+///
+/// The file is left unlocked if this fails.
+fn lock_file(file: &File, mode: LockMode, deadline: Instant) -> Result<(), LockError> {
+    poll_lock_file(file, mode, deadline)?;
+    debug!("locked archive ({:?})", mode);
+
+    // Holders of the exclusive lock record themselves in the lock file. If the file is locked
+    // while a holder is still recorded, that holder didn't release the lock cleanly.
+    if let Some(holder) = read_holder(file) {
+        warn!(
+            "archive lock was previously held by {}, which didn't release it cleanly",
+            holder
+        );
+    }
+    let holder = (mode == LockMode::Exclusive).then(LockHolder::current);
+    if let Err(err) = write_holder(file, holder.as_ref()) {
+        if let Err(err) = file.unlock() {
+            warn!("failed to release archive lock: {}", err);
+        }
+        return Err(LockError::Lock(err));
+    }
+    Ok(())
+}
+
+/// Tries to lock the lock file until it succeeds, or the deadline passes.
+fn poll_lock_file(file: &File, mode: LockMode, deadline: Instant) -> Result<(), LockError> {
+    let mut poll_interval = Duration::from_millis(1);
+    loop {
+        let result = match mode {
+            LockMode::Shared => file.try_lock_shared(),
+            LockMode::Exclusive => file.try_lock(),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) => (),
+            Err(TryLockError::Error(err)) => return Err(LockError::Lock(err)),
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            // Only the exclusive holder is recorded, since shared holders clear the record.
+            // If there's no record, the file is locked by shared holders.
+            let holder = read_holder(file);
+            if let Some(holder) = holder.as_ref().filter(|holder| holder.is_dead()) {
+                return Err(LockError::Stale(holder.clone()));
+            }
+            return Err(LockError::Timeout { holder });
+        }
+        thread::sleep(poll_interval.min(remaining));
+        poll_interval = (poll_interval * 2).min(MAX_POLL_INTERVAL);
+    }
+}
+
+/// Releases the lock on the lock file, clearing the record of the exclusive holder.
+fn unlock_file(file: &File) -> io::Result<()> {
+    write_holder(file, None)?;
+    file.unlock()
+}
+
+/// Reads the process holding the exclusive lock from the lock file.
+fn read_holder(mut file: &File) -> Option<LockHolder> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    let (pid, host) = contents.trim().split_once(' ')?;
+    Some(LockHolder {
+        pid: pid.parse().ok()?,
+        host: host.to_owned(),
+    })
+}
+
+/// Records the process holding the exclusive lock in the lock file, or clears it.
+fn write_holder(mut file: &File, holder: Option<&LockHolder>) -> io::Result<()> {
+    if holder.is_none() && file.metadata()?.len() == 0 {
+        return Ok(());
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    if let Some(holder) = holder {
+        writeln!(file, "{} {}", holder.pid, holder.host)?;
+    }
+    file.sync_data()
+}
+
+/// A lock on an archive, created by [`MediaArchive::lock`].
+///
+/// The lock is released when this is dropped.
+#[derive(Debug)]
+#[must_use = "the archive is unlocked when the lock is dropped"]
+pub struct ArchiveLock {
+    state: Arc<ArchiveLockState>,
+    exclusive: bool,
+    thread: ThreadId,
+    /// Whether the lock file was locked to take this lock, rather than being already locked by the process.
+    locked_file: bool,
+}
+
+impl Drop for ArchiveLock {
+    fn drop(&mut self) {
+        self.state.release(self.exclusive, self.thread);
+    }
+}
+
+/// The process that holds, or held, the exclusive lock on an archive.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LockHolder {
+    /// The process ID of the process.
+    pub pid: u32,
+    /// The name of the host the process runs on.
+    pub host: String,
+}
+
+impl LockHolder {
+    pub(crate) fn current() -> Self {
+        LockHolder {
+            pid: std::process::id(),
+            host: host_name(),
+        }
+    }
+
+    /// Returns whether the process is known to not exist anymore.
+    ///
+    /// This can only be known for processes on the current host, on systems with `/proc`.
+    pub(crate) fn is_dead(&self) -> bool {
+        self.host == host_name()
+            && Path::new("/proc/self").exists()
+            && !Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+}
+
+impl Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "process {} on '{}'", self.pid, self.host)
+    }
+}
+
+pub(crate) fn host_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map_or_else(String::new, |name| name.trim().to_owned())
+}
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] io::Error),
+    #[error("failed to open the archive's lock file: {0}")]
+    Open(#[source] io::Error),
+    /// The process recorded as holding the exclusive lock doesn't exist anymore, but the lock is still held,
+    /// such as by a network file system that didn't notice the process exited.
+    #[error("the archive is locked by {0}, which doesn't exist anymore")]
+    Stale(LockHolder),
+    /// Stored files were moved by [`MediaArchive::migrate_layout`] through another handle to the archive,
+    /// so this handle would look for them in the wrong place. The archive must be opened again.
+    #[error("the archive's store layout was changed by another handle, it must be opened again")]
+    StoreLayoutChanged,
+    #[error("timed out waiting for the archive to be unlocked{}", .holder.as_ref().map(|holder| format!(" by {holder}")).unwrap_or_default())]
+    Timeout { holder: Option<LockHolder> },
+    #[error("cannot lock the archive exclusively while holding a shared lock on it")]
+    Upgrade,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::temp_media_archive;
+    use crate::{DeployMethod, DeploymentTarget, DiskStructure};
+
+    const SHORT_TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn lock_modes() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        // A separately opened archive behaves like another process, since it has its own lock file handle.
+        let mut other = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        other.set_lock_timeout(SHORT_TIMEOUT);
+
+        let shared = archive.lock(LockMode::Shared).unwrap();
+        let nested = archive.lock(LockMode::Shared).unwrap();
+        drop(other.lock(LockMode::Shared).unwrap());
+        assert!(matches!(
+            other.lock(LockMode::Exclusive),
+            Err(LockError::Timeout { holder: None })
+        ));
+        drop(shared);
+        drop(nested);
+
+        let exclusive = archive.lock(LockMode::Exclusive).unwrap();
+        drop(archive.lock(LockMode::Shared).unwrap());
+        match other.lock(LockMode::Shared) {
+            Err(LockError::Timeout { holder: Some(holder) }) => assert_eq!(holder, LockHolder::current()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        drop(exclusive);
+
+        drop(other.lock(LockMode::Exclusive).unwrap());
+    }
+
+    #[test]
+    fn upgrade_is_refused() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let mut other = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        other.set_lock_timeout(SHORT_TIMEOUT);
+
+        let shared = archive.lock(LockMode::Shared).unwrap();
+        assert!(matches!(archive.lock(LockMode::Exclusive), Err(LockError::Upgrade)));
+
+        // The shared lock is still held after the failed upgrade.
+        assert!(matches!(
+            other.lock(LockMode::Exclusive),
+            Err(LockError::Timeout { holder: None })
+        ));
+        drop(archive.lock(LockMode::Shared).unwrap());
+        drop(shared);
+
+        drop(archive.lock(LockMode::Exclusive).unwrap());
+        drop(other.lock(LockMode::Exclusive).unwrap());
+    }
+
+    #[test]
+    fn stale_holder_is_only_reported_when_blocking() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let mut other = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Bare).unwrap();
+        other.set_lock_timeout(SHORT_TIMEOUT);
+
+        // A process that crashed while holding the exclusive lock leaves its record behind.
+        let dead_holder = LockHolder {
+            pid: u32::MAX,
+            host: host_name(),
+        };
+        fs::write(
+            temp_dir.join(LOCK_FILE),
+            format!("{} {}\n", dead_holder.pid, dead_holder.host),
+        )
+        .unwrap();
+
+        let shared = archive.lock(LockMode::Shared).unwrap();
+        assert!(matches!(
+            other.lock(LockMode::Exclusive),
+            Err(LockError::Timeout { holder: None })
+        ));
+        drop(shared);
+
+        drop(other.lock(LockMode::Exclusive).unwrap());
+    }
+
+    #[test]
+    fn lock_is_shared_between_targets() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Bare);
+        archive
+            .add_target(
+                "other",
+                DeploymentTarget {
+                    path: temp_dir.join("other"),
+                    default_method: DeployMethod::Copy,
+                },
+            )
+            .unwrap();
+        let mut target = archive.target("other").unwrap();
+        target.set_lock_timeout(SHORT_TIMEOUT);
+
+        let _exclusive = archive.lock(LockMode::Exclusive).unwrap();
+        drop(target.lock(LockMode::Exclusive).unwrap());
+    }
+
+    #[test]
+    fn lock_waits_for_other_threads() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let archive = Arc::new(archive);
+
+        let exclusive = archive.lock(LockMode::Exclusive).unwrap();
+        let waiter = {
+            let archive = Arc::clone(&archive);
+            thread::spawn(move || {
+                let _shared = archive.lock(LockMode::Shared).unwrap();
+                Instant::now()
+            })
+        };
+        thread::sleep(SHORT_TIMEOUT);
+        let released_at = Instant::now();
+        drop(exclusive);
+        assert!(waiter.join().unwrap() >= released_at);
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+use crate::{read_hash_directory, BlobError, Hash, LockError, LockMode, MediaArchive, StoreBlobError};
+
+const MANIFESTS_DIRECTORY: &str = "manifests";
+const MANIFEST_FORMAT: &str = "media-archive-manifest";
+const MANIFEST_VERSION: u32 = 1;
+
+impl MediaArchive {
+    /// Stores a manifest in the archive, and returns its hash.
+    ///
+    /// Manifests are stored like any other file, so the same manifest always has the same hash.
+    /// Saved manifests, and the files they reference, are never garbage collected.
+    #[tracing::instrument(skip_all, err)]
+    pub fn save_manifest(&self, manifest: &Manifest) -> Result<Hash, ManifestError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(ManifestError::Lock)?;
+        let contents = serde_json::to_vec(&ManifestFileRef {
+            format: MANIFEST_FORMAT,
+            version: MANIFEST_VERSION,
+            entries: &manifest.entries,
+        })
+        .expect("manifest serialization should not fail");
+        let hash = self
+            .store_reader(contents.as_slice())
+            .map_err(ManifestError::Store)?
+            .hash();
+
+        let manifests_path = self.archive_path.join(MANIFESTS_DIRECTORY);
+        fs::create_dir_all(&manifests_path).map_err(ManifestError::Index)?;
+        File::create(manifests_path.join(hash.to_hex().as_str())).map_err(ManifestError::Index)?;
+
+        info!("saved manifest with hash '{}'", hash);
+        Ok(hash)
+    }
+
+    /// Loads a manifest stored in the archive.
+    #[tracing::instrument(skip(self), err)]
+    pub fn load_manifest(&self, hash: &Hash) -> Result<Manifest, ManifestError> {
+        let file = self.open_blob(hash).map_err(ManifestError::Read)?;
+        let manifest: ManifestFile =
+            serde_json::from_reader(BufReader::new(file)).map_err(|_| ManifestError::NotAManifest(*hash))?;
+
+        if manifest.format != MANIFEST_FORMAT {
+            return Err(ManifestError::NotAManifest(*hash));
+        }
+        if manifest.version != MANIFEST_VERSION {
+            return Err(ManifestError::UnsupportedVersion(manifest.version));
+        }
+
+        Ok(Manifest {
+            entries: manifest.entries,
+        })
+    }
+
+    /// Returns the hashes of the saved manifests.
+    pub fn manifests(&self) -> Result<Vec<Hash>, ManifestError> {
+        read_hash_directory(&self.archive_path.join(MANIFESTS_DIRECTORY)).map_err(ManifestError::Index)
+    }
+
+    /// Removes a manifest from the list of saved manifests.
+    ///
+    /// The manifest itself stays in the store until it's garbage collected.
+    #[tracing::instrument(skip(self), err)]
+    pub fn remove_manifest(&self, hash: &Hash) -> Result<(), ManifestError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(ManifestError::Lock)?;
+        let index_path = self.archive_path.join(MANIFESTS_DIRECTORY).join(hash.to_hex().as_str());
+        match fs::remove_file(index_path) {
+            Ok(()) => {
+                info!("removed manifest successfully");
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Err(ManifestError::NotAManifest(*hash)),
+            Err(err) => Err(ManifestError::Index(err)),
+        }
+    }
+
+    /// Creates a manifest from stored files and the paths they should have.
+    ///
+    /// The sizes of the files are taken from the store, and their mode is set to
+    /// [`ManifestEntry::DEFAULT_MODE`].
+    pub fn manifest_from_files(
+        &self,
+        files: impl IntoIterator<Item = (RelativePathBuf, Hash)>,
+    ) -> Result<Manifest, BlobError> {
+        let mut manifest = Manifest::new();
+        for (path, hash) in files {
+            let size = self.blob_len(&hash)?;
+            manifest.insert(
+                &path,
+                ManifestEntry {
+                    hash,
+                    size,
+                    mode: ManifestEntry::DEFAULT_MODE,
+                },
+            );
+        }
+        Ok(manifest)
+    }
+}
+
+/// A mapping of paths to stored files, describing the layout of a collection of files.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Manifest {
+    entries: BTreeMap<RelativePathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry to the manifest, returning the entry previously at the same path, if any.
+    ///
+    /// `path` is normalized before being added.
+    pub fn insert(&mut self, path: &RelativePath, entry: ManifestEntry) -> Option<ManifestEntry> {
+        self.entries.insert(path.normalize(), entry)
+    }
+
+    /// Removes the entry at `path` from the manifest, and returns it.
+    pub fn remove(&mut self, path: &RelativePath) -> Option<ManifestEntry> {
+        self.entries.remove(&path.normalize())
+    }
+
+    /// Returns the entry at `path`.
+    #[must_use]
+    pub fn get(&self, path: &RelativePath) -> Option<&ManifestEntry> {
+        self.entries.get(&path.normalize())
+    }
+
+    /// Returns an iterator over the entries of the manifest, sorted by path.
+    pub fn iter(&self) -> impl Iterator<Item = (&RelativePath, &ManifestEntry)> {
+        self.entries
+            .iter()
+            .map(|(path, entry)| (path.as_relative_path(), entry))
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A file in a [`Manifest`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The hash of the stored file.
+    #[serde(with = "hash_hex")]
+    pub hash: Hash,
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The Unix permission bits of the file.
+    pub mode: u32,
+}
+
+impl ManifestEntry {
+    /// The mode of files whose mode isn't known.
+    pub const DEFAULT_MODE: u32 = 0o644;
+}
+
+#[derive(Serialize)]
+struct ManifestFileRef<'a> {
+    format: &'static str,
+    version: u32,
+    entries: &'a BTreeMap<RelativePathBuf, ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+struct ManifestFile {
+    format: String,
+    version: u32,
+    entries: BTreeMap<RelativePathBuf, ManifestEntry>,
+}
+
+/// (De)serializes hashes as hexadecimal strings.
+pub(crate) mod hash_hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::Hash;
+
+    pub fn serialize<S: Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(hash.to_hex().as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Hash, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Hash::from_hex(hex).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("failed to access the list of saved manifests: {0}")]
+    Index(#[source] io::Error),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+    #[error("file with hash '{0}' is not a manifest")]
+    NotAManifest(Hash),
+    #[error("failed to read manifest: {0}")]
+    Read(#[source] BlobError),
+    #[error("failed to store manifest: {0}")]
+    Store(#[source] StoreBlobError),
+    #[error("manifest format version {0} is not supported")]
+    UnsupportedVersion(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::DiskStructure;
+
+    #[test]
+    fn save_and_load_manifest() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        let mut manifest = archive
+            .manifest_from_files([(RelativePathBuf::from("a/b.txt"), hash)])
+            .unwrap();
+        manifest.insert(
+            RelativePath::new("./c/../d.txt"),
+            ManifestEntry {
+                hash,
+                size: TEST_DATA.len() as u64,
+                mode: 0o755,
+            },
+        );
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(
+            manifest.get(RelativePath::new("a/b.txt")).unwrap().size,
+            TEST_DATA.len() as u64
+        );
+        assert_eq!(manifest.get(RelativePath::new("d.txt")).unwrap().mode, 0o755);
+
+        let manifest_hash = archive.save_manifest(&manifest).unwrap();
+        assert_eq!(archive.save_manifest(&manifest).unwrap(), manifest_hash);
+        assert_eq!(archive.manifests().unwrap(), [manifest_hash]);
+        assert_eq!(archive.load_manifest(&manifest_hash).unwrap(), manifest);
+
+        archive.remove_manifest(&manifest_hash).unwrap();
+        assert!(archive.manifests().unwrap().is_empty());
+        assert!(archive.contains(&manifest_hash));
+    }
+
+    #[test]
+    fn load_manifest_not_a_manifest() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        assert!(matches!(
+            archive.load_manifest(&hash),
+            Err(ManifestError::NotAManifest(_))
+        ));
+    }
+}
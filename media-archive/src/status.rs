@@ -0,0 +1,257 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use relative_path::{RelativePath, RelativePathBuf};
+use tracing::info;
+
+use crate::deploy::file_has_hash;
+use crate::deployment::{DeploymentRecord, FileStamp};
+use crate::{DeployError, DeployMethod, LockMode, MediaArchive, SymlinkStyle};
+
+impl MediaArchive {
+    /// Compares the deployment directory with the record of the files deployed by the archive.
+    ///
+    /// Only files that changed since being deployed, and files that weren't deployed by the archive,
+    /// are reported. The size and modification time of files found to be unchanged are remembered,
+    /// so that they are only hashed again once either of them changes.
+    #[tracing::instrument(skip(self), err)]
+    pub fn status(&self) -> Result<StatusReport, DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        if self.deploy_path.is_none() {
+            return Err(DeployError::IsBareArchive);
+        }
+
+        let mut state = self.load_deployment_state()?;
+        let mut report = StatusReport::default();
+        let mut stamps_changed = false;
+        for (path, record) in &mut state.files {
+            let previous_stamp = record.stamp;
+            match self.deployed_file_status(path, record) {
+                Ok(Some(status)) => {
+                    report.files.insert(path.clone(), Ok(status));
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    report.files.insert(path.clone(), Err(err));
+                }
+            }
+            stamps_changed |= record.stamp != previous_stamp;
+        }
+
+        for path in self.scan_deploy_directory()? {
+            if !state.files.contains_key(&path) {
+                report.files.insert(path, Ok(FileStatus::Untracked));
+            }
+        }
+
+        if stamps_changed {
+            self.save_deployment_state(&state)?;
+        }
+
+        info!("found {} changed or untracked files", report.files.len());
+        Ok(report)
+    }
+
+    /// Returns how a deployed file changed since being deployed, or `None` if it didn't change.
+    fn deployed_file_status(
+        &self,
+        path: &RelativePath,
+        record: &mut DeploymentRecord,
+    ) -> Result<Option<FileStatus>, DeployError> {
+        let target_path = self.deploy_target_path(path)?;
+        let metadata_error = |err| DeployError::Metadata {
+            path: target_path.clone(),
+            source: err,
+        };
+
+        let symlink_metadata = match target_path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Some(FileStatus::Deleted)),
+            Err(err) => return Err(metadata_error(err)),
+        };
+        // Rooted symlinks don't necessarily resolve from where the archive is,
+        // so they are only checked to point where they were made to point.
+        if record.method == DeployMethod::Symlink
+            && matches!(self.config.symlink_style, SymlinkStyle::Rooted { .. })
+            && self.is_deployed_symlink(&target_path, record)
+        {
+            return Ok(None);
+        }
+
+        let metadata = match target_path.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Some(FileStatus::BrokenSymlink)),
+            Err(err) => return Err(metadata_error(err)),
+        };
+
+        if record.method == DeployMethod::Symlink {
+            if !symlink_metadata.is_symlink() {
+                return Ok(Some(FileStatus::ReplacedSymlink));
+            }
+            let link_target = target_path.canonicalize().map_err(metadata_error)?;
+            let points_to_stored_file = self
+                .get_path_of_stored_file(&record.hash)
+                .canonicalize()
+                .is_ok_and(|stored_path| stored_path == link_target);
+            return Ok((!points_to_stored_file).then_some(FileStatus::ReplacedSymlink));
+        }
+
+        if !metadata.is_file() {
+            return Ok(Some(FileStatus::Modified));
+        }
+        let stamp = FileStamp::from_metadata(&metadata);
+        if stamp.is_some() && stamp == record.stamp {
+            return Ok(None);
+        }
+        if !file_has_hash(&target_path, &record.hash, None)? {
+            return Ok(Some(FileStatus::Modified));
+        }
+        record.stamp = stamp;
+        Ok(None)
+    }
+}
+
+/// How a file in the deployment directory differs from the record of deployed files.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileStatus {
+    /// The file is a symlink to a file that doesn't exist.
+    BrokenSymlink,
+    /// The file was deployed, but doesn't exist anymore.
+    Deleted,
+    /// The contents of the file changed since it was deployed.
+    Modified,
+    /// The file was deployed as a symlink, but was replaced by another file, or by a symlink to another file.
+    ReplacedSymlink,
+    /// The file wasn't deployed by the archive.
+    Untracked,
+}
+
+/// The result of comparing the deployment directory with the record of deployed files
+/// with [`MediaArchive::status`].
+#[derive(Debug, Default)]
+pub struct StatusReport {
+    /// The status of each file that changed or wasn't deployed by the archive,
+    /// indexed by its path relative to the deployment directory.
+    ///
+    /// Unchanged files are not included.
+    pub files: BTreeMap<RelativePathBuf, Result<FileStatus, DeployError>>,
+}
+
+impl StatusReport {
+    /// Returns an iterator over the files whose status couldn't be determined.
+    pub fn errors(&self) -> impl Iterator<Item = (&RelativePath, &DeployError)> {
+        self.files
+            .iter()
+            .filter_map(|(path, result)| result.as_ref().err().map(|err| (path.as_relative_path(), err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, File};
+
+    use assert_fs::prelude::*;
+
+    use crate::deploy::set_mode;
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::DiskStructure;
+
+    fn status_of(report: &StatusReport, path: &str) -> FileStatus {
+        *report.files[RelativePath::new(path)].as_ref().unwrap()
+    }
+
+    #[test]
+    fn status() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let other_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+        for (path, method) in [
+            ("unchanged.txt", DeployMethod::Copy),
+            ("modified.txt", DeployMethod::Copy),
+            ("deleted.txt", DeployMethod::Hardlink),
+            ("broken.txt", DeployMethod::Copy),
+            ("linked.txt", DeployMethod::Symlink),
+            ("replaced.txt", DeployMethod::Symlink),
+            ("relinked.txt", DeployMethod::Symlink),
+        ] {
+            archive.deploy_file(&hash, RelativePath::new(path), method).unwrap();
+        }
+
+        let report = archive.status().unwrap();
+        assert!(report.files.is_empty(), "unexpected changes: {:?}", report.files);
+
+        set_mode(temp_dir.child("modified.txt").path(), 0o644);
+        temp_dir.child("modified.txt").write_str("modified data").unwrap();
+        fs::remove_file(temp_dir.child("deleted.txt")).unwrap();
+        fs::remove_file(temp_dir.child("broken.txt")).unwrap();
+        temp_dir.child("broken.txt").symlink_to_file("missing.txt").unwrap();
+        fs::remove_file(temp_dir.child("replaced.txt")).unwrap();
+        temp_dir.child("replaced.txt").write_str(TEST_DATA).unwrap();
+        fs::remove_file(temp_dir.child("relinked.txt")).unwrap();
+        archive
+            .deploy_file(&other_hash, RelativePath::new("other.txt"), DeployMethod::Copy)
+            .unwrap();
+        temp_dir.child("relinked.txt").symlink_to_file("other.txt").unwrap();
+        temp_dir.child("user/untracked.txt").write_str("user data").unwrap();
+
+        let report = archive.status().unwrap();
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(report.files.len(), 6);
+        assert_eq!(status_of(&report, "modified.txt"), FileStatus::Modified);
+        assert_eq!(status_of(&report, "deleted.txt"), FileStatus::Deleted);
+        assert_eq!(status_of(&report, "broken.txt"), FileStatus::BrokenSymlink);
+        assert_eq!(status_of(&report, "replaced.txt"), FileStatus::ReplacedSymlink);
+        assert_eq!(status_of(&report, "relinked.txt"), FileStatus::ReplacedSymlink);
+        assert_eq!(status_of(&report, "user/untracked.txt"), FileStatus::Untracked);
+    }
+
+    #[test]
+    fn status_skips_hashing_unchanged_files() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        archive
+            .deploy_file(&hash, RelativePath::new("a.txt"), DeployMethod::Copy)
+            .unwrap();
+        assert!(archive.status().unwrap().files.is_empty());
+
+        // Change the contents, but keep the size and modification time,
+        // so the change can only be detected by hashing the file.
+        let path = temp_dir.child("a.txt");
+        let modified = path.metadata().unwrap().modified().unwrap();
+        set_mode(path.path(), 0o644);
+        path.write_str(&"x".repeat(TEST_DATA.len())).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(archive.status().unwrap().files.is_empty());
+
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(1))
+            .unwrap();
+        let report = archive.status().unwrap();
+        assert_eq!(status_of(&report, "a.txt"), FileStatus::Modified);
+    }
+}
@@ -0,0 +1,476 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use relative_path::{RelativePath, RelativePathBuf};
+use tracing::{info, warn};
+
+use crate::deploy::set_mode;
+use crate::deployment::{remove_empty_parent_directories, DeploymentRecord, DeploymentState};
+use crate::{DeployError, DeployMethod, LockMode, Manifest, ManifestEntry, MediaArchive};
+
+impl MediaArchive {
+    /// Brings the deployment directory in line with a manifest.
+    ///
+    /// Files deployed by the archive that aren't in the manifest are removed, files whose hash
+    /// differs from the one in the manifest are replaced, and missing files are deployed.
+    /// Deployed files that were modified since being deployed are left untouched, and reported
+    /// as [`DeployError::Modified`].
+    ///
+    /// Files that weren't deployed by the archive are only removed or replaced if `options` allows it.
+    ///
+    /// Failing to synchronize a file doesn't abort the operation. Instead, the result of
+    /// synchronizing each file is collected in the returned [`SyncReport`].
+    #[tracing::instrument(skip(self, manifest), err)]
+    pub fn sync_deployment(
+        &self,
+        manifest: &Manifest,
+        method: DeployMethod,
+        options: SyncOptions,
+    ) -> Result<SyncReport, DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        if self.deploy_path.is_none() {
+            return Err(DeployError::IsBareArchive);
+        }
+
+        let journal = self.begin_deploy(manifest.iter().map(|(path, entry)| (path, entry.hash, method)))?;
+        let mut state = self.load_deployment_state()?;
+        let mut report = SyncReport::default();
+
+        let stale_files: Vec<(RelativePathBuf, DeploymentRecord)> = state
+            .files
+            .iter()
+            .filter(|(path, _)| manifest.get(path).is_none())
+            .map(|(path, deployed)| (path.clone(), *deployed))
+            .collect();
+        for (path, deployed) in stale_files {
+            match self.remove_deployed_file(&path, &deployed) {
+                Ok(removed) => {
+                    state.files.remove(&path);
+                    if removed {
+                        report.changes.insert(path, Ok(SyncChange::Removed));
+                    }
+                }
+                Err(err) => {
+                    warn!("failed to remove '{}': {}", path, err);
+                    report.changes.insert(path, Err(err));
+                }
+            }
+        }
+
+        for (path, entry) in manifest.iter() {
+            match self.sync_manifest_entry(&mut state, path, entry, method, options) {
+                Ok(Some(change)) => {
+                    report.changes.insert(path.to_owned(), Ok(change));
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    warn!("failed to synchronize '{}': {}", path, err);
+                    report.changes.insert(path.to_owned(), Err(err));
+                }
+            }
+        }
+
+        if options.remove_untracked {
+            for path in self.scan_deploy_directory()? {
+                if state.files.contains_key(&path) || manifest.get(&path).is_some() {
+                    continue;
+                }
+                let result = self.remove_untracked_file(&path);
+                if let Err(err) = &result {
+                    warn!("failed to remove '{}': {}", path, err);
+                }
+                report.changes.insert(path, result);
+            }
+        }
+
+        self.save_deployment_state(&state)?;
+        journal.complete();
+
+        info!(
+            "synchronized deployment, {} changes, {} files failed to synchronize",
+            report.changes.len() - report.errors().count(),
+            report.errors().count()
+        );
+        Ok(report)
+    }
+
+    /// Deploys again the files deployed by the archive that were removed from the deployment directory.
+    ///
+    /// Each file is deployed with the method it was originally deployed with.
+    /// Files that still exist are left untouched, even if they were modified.
+    #[tracing::instrument(skip(self), err)]
+    pub fn resync_deployment(&self) -> Result<SyncReport, DeployError> {
+        let _lock = self.lock(LockMode::Exclusive).map_err(DeployError::Lock)?;
+        if self.deploy_path.is_none() {
+            return Err(DeployError::IsBareArchive);
+        }
+
+        let mut state = self.load_deployment_state()?;
+        let journal = self.begin_deploy(
+            state
+                .files
+                .iter()
+                .map(|(path, record)| (path.as_relative_path(), record.hash, record.method)),
+        )?;
+        let mut report = SyncReport::default();
+        for (path, record) in &mut state.files {
+            match self.redeploy_missing_file(path, record) {
+                Ok(Some(change)) => {
+                    report.changes.insert(path.clone(), Ok(change));
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    warn!("failed to deploy '{}' again: {}", path, err);
+                    report.changes.insert(path.clone(), Err(err));
+                }
+            }
+        }
+        self.save_deployment_state(&state)?;
+        journal.complete();
+
+        info!(
+            "resynchronized deployment, {} files deployed again, {} files failed to deploy",
+            report.changes.len() - report.errors().count(),
+            report.errors().count()
+        );
+        Ok(report)
+    }
+
+    fn redeploy_missing_file(
+        &self,
+        path: &RelativePath,
+        record: &mut DeploymentRecord,
+    ) -> Result<Option<SyncChange>, DeployError> {
+        let target_path = self.deploy_target_path(path)?;
+        match target_path.symlink_metadata() {
+            Ok(_) => return Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => {
+                return Err(DeployError::Metadata {
+                    path: target_path,
+                    source: err,
+                })
+            }
+        }
+
+        self.deploy_to(&record.hash, &target_path, record.method)?;
+        *record = DeploymentRecord::new(record.hash, record.method);
+        Ok(Some(SyncChange::Added))
+    }
+
+    fn sync_manifest_entry(
+        &self,
+        state: &mut DeploymentState,
+        path: &RelativePath,
+        entry: &ManifestEntry,
+        method: DeployMethod,
+        options: SyncOptions,
+    ) -> Result<Option<SyncChange>, DeployError> {
+        let target_path = self.deploy_target_path(path)?;
+
+        let existing_metadata = match target_path.symlink_metadata() {
+            Ok(metadata) => Some(metadata),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(DeployError::Metadata {
+                    path: target_path,
+                    source: err,
+                })
+            }
+        };
+        let deployed = state.files.get(path).copied();
+
+        let (change, method) = match (existing_metadata, deployed) {
+            (None, _) => (SyncChange::Added, self.deploy_to(&entry.hash, &target_path, method)?),
+            (Some(metadata), _) if metadata.is_dir() => return Err(DeployError::AlreadyExists(target_path)),
+            // Files are only hashed if they need to be replaced, to keep synchronizing cheap.
+            (Some(_), Some(deployed)) if deployed.hash == entry.hash => return Ok(None),
+            (Some(_), Some(deployed)) => {
+                if !self.deployed_file_is_unmodified(&target_path, &deployed)? {
+                    return Err(DeployError::Modified(target_path));
+                }
+                (
+                    SyncChange::Replaced,
+                    self.replace_deployed_file(&entry.hash, &target_path, method)?,
+                )
+            }
+            (Some(_), None) if options.overwrite_untracked => (
+                SyncChange::Replaced,
+                self.replace_deployed_file(&entry.hash, &target_path, method)?,
+            ),
+            (Some(_), None) => return Err(DeployError::AlreadyExists(target_path)),
+        };
+
+        if matches!(method, DeployMethod::Copy | DeployMethod::Reflink) {
+            set_mode(&target_path, entry.mode);
+        }
+        state
+            .files
+            .insert(path.to_owned(), DeploymentRecord::new(entry.hash, method));
+        Ok(Some(change))
+    }
+
+    /// Removes a file that wasn't deployed by the archive.
+    fn remove_untracked_file(&self, path: &RelativePath) -> Result<SyncChange, DeployError> {
+        let target_path = self.deploy_target_path(path)?;
+        fs::remove_file(&target_path).map_err(|err| DeployError::Remove {
+            path: target_path.clone(),
+            source: err,
+        })?;
+
+        let deploy_path = self.deploy_path.as_ref().expect("deploy path was checked to exist");
+        remove_empty_parent_directories(&target_path, deploy_path);
+        Ok(SyncChange::Removed)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SyncOptions {
+    /// Whether to remove files that weren't deployed by the archive and aren't in the manifest.
+    pub remove_untracked: bool,
+    /// Whether to replace files that weren't deployed by the archive, but are in the way of a file in the manifest.
+    pub overwrite_untracked: bool,
+}
+
+/// A change made to the deployment directory by [`MediaArchive::sync_deployment`]
+/// or [`MediaArchive::resync_deployment`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SyncChange {
+    /// The file was deployed.
+    Added,
+    /// The file was removed.
+    Removed,
+    /// The file was replaced with the file in the manifest.
+    Replaced,
+}
+
+/// The result of synchronizing the deployment directory with [`MediaArchive::sync_deployment`]
+/// or [`MediaArchive::resync_deployment`].
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The changes made to each file, indexed by its path relative to the deployment directory.
+    ///
+    /// Files that were already up to date are not included.
+    pub changes: BTreeMap<RelativePathBuf, Result<SyncChange, DeployError>>,
+}
+
+impl SyncReport {
+    /// Returns an iterator over the files that failed to synchronize.
+    pub fn errors(&self) -> impl Iterator<Item = (&RelativePath, &DeployError)> {
+        self.changes
+            .iter()
+            .filter_map(|(path, result)| result.as_ref().err().map(|err| (path.as_relative_path(), err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+    use predicates::prelude::*;
+
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::{DiskStructure, Hash};
+
+    fn stored_hashes(archive: &MediaArchive) -> (Hash, Hash) {
+        let test_data_hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+        let other_data_hash = archive.store_reader("other data".as_bytes()).unwrap().hash();
+        (test_data_hash, other_data_hash)
+    }
+
+    #[test]
+    fn sync_deployment() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let (test_data_hash, other_data_hash) = stored_hashes(&archive);
+
+        let manifest = archive
+            .manifest_from_files([
+                (RelativePathBuf::from("a.txt"), test_data_hash),
+                (RelativePathBuf::from("b/c.txt"), other_data_hash),
+            ])
+            .unwrap();
+        let report = archive
+            .sync_deployment(&manifest, DeployMethod::Copy, SyncOptions::default())
+            .unwrap();
+        assert_eq!(report.changes.len(), 2);
+        assert!(report
+            .changes
+            .values()
+            .all(|result| matches!(result, Ok(SyncChange::Added))));
+
+        let report = archive
+            .sync_deployment(&manifest, DeployMethod::Copy, SyncOptions::default())
+            .unwrap();
+        assert!(report.changes.is_empty());
+
+        let manifest = archive
+            .manifest_from_files([
+                (RelativePathBuf::from("a.txt"), other_data_hash),
+                (RelativePathBuf::from("d.txt"), test_data_hash),
+            ])
+            .unwrap();
+        let report = archive
+            .sync_deployment(&manifest, DeployMethod::Copy, SyncOptions::default())
+            .unwrap();
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(
+            report.changes[RelativePath::new("a.txt")].as_ref().unwrap(),
+            &SyncChange::Replaced
+        );
+        assert_eq!(
+            report.changes[RelativePath::new("b/c.txt")].as_ref().unwrap(),
+            &SyncChange::Removed
+        );
+        assert_eq!(
+            report.changes[RelativePath::new("d.txt")].as_ref().unwrap(),
+            &SyncChange::Added
+        );
+
+        temp_dir.child("a.txt").assert("other data");
+        temp_dir.child("b").assert(predicate::path::missing());
+        temp_dir.child("d.txt").assert(TEST_DATA);
+    }
+
+    #[test]
+    fn sync_deployment_untracked_files() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let (test_data_hash, _) = stored_hashes(&archive);
+        temp_dir.child("a.txt").write_str("user data").unwrap();
+        temp_dir.child("b/user.txt").write_str("user data").unwrap();
+
+        let manifest = archive
+            .manifest_from_files([(RelativePathBuf::from("a.txt"), test_data_hash)])
+            .unwrap();
+        let report = archive
+            .sync_deployment(&manifest, DeployMethod::Hardlink, SyncOptions::default())
+            .unwrap();
+        assert_eq!(report.changes.len(), 1);
+        assert!(matches!(
+            report.changes[RelativePath::new("a.txt")],
+            Err(DeployError::AlreadyExists(_))
+        ));
+        temp_dir.child("a.txt").assert("user data");
+        temp_dir.child("b/user.txt").assert("user data");
+
+        let report = archive
+            .sync_deployment(
+                &manifest,
+                DeployMethod::Hardlink,
+                SyncOptions {
+                    remove_untracked: true,
+                    overwrite_untracked: true,
+                },
+            )
+            .unwrap();
+        assert_eq!(report.errors().count(), 0);
+        assert_eq!(
+            report.changes[RelativePath::new("a.txt")].as_ref().unwrap(),
+            &SyncChange::Replaced
+        );
+        assert_eq!(
+            report.changes[RelativePath::new("b/user.txt")].as_ref().unwrap(),
+            &SyncChange::Removed
+        );
+        temp_dir.child("a.txt").assert(TEST_DATA);
+        temp_dir.child("b").assert(predicate::path::missing());
+        temp_dir.child(".media-archive").assert(predicate::path::is_dir());
+    }
+
+    #[test]
+    fn sync_deployment_inside_archive() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let (test_data_hash, _) = stored_hashes(&archive);
+
+        let manifest = archive
+            .manifest_from_files([(RelativePathBuf::from(".media-archive/config.json"), test_data_hash)])
+            .unwrap();
+        let report = archive
+            .sync_deployment(
+                &manifest,
+                DeployMethod::Copy,
+                SyncOptions {
+                    remove_untracked: true,
+                    overwrite_untracked: true,
+                },
+            )
+            .unwrap();
+        assert!(matches!(
+            report.changes[RelativePath::new(".media-archive/config.json")],
+            Err(DeployError::InsideArchive(_))
+        ));
+        assert!(archive.deployments().unwrap().is_empty());
+        MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+    }
+
+    #[test]
+    fn sync_deployment_keeps_modified_files() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let (test_data_hash, _) = stored_hashes(&archive);
+        archive
+            .deploy_file(&test_data_hash, RelativePath::new("a.txt"), DeployMethod::Copy)
+            .unwrap();
+        set_mode(temp_dir.child("a.txt").path(), 0o644);
+        temp_dir.child("a.txt").write_str("modified data").unwrap();
+
+        let report = archive
+            .sync_deployment(&Manifest::new(), DeployMethod::Copy, SyncOptions::default())
+            .unwrap();
+        assert!(matches!(
+            report.changes[RelativePath::new("a.txt")],
+            Err(DeployError::Modified(_))
+        ));
+        temp_dir.child("a.txt").assert("modified data");
+    }
+
+    #[test]
+    fn resync_deployment() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let (test_data_hash, other_data_hash) = stored_hashes(&archive);
+        archive
+            .deploy_file(&test_data_hash, RelativePath::new("a.txt"), DeployMethod::Hardlink)
+            .unwrap();
+        archive
+            .deploy_file(&other_data_hash, RelativePath::new("b/c.txt"), DeployMethod::Symlink)
+            .unwrap();
+        let deployed_at = archive
+            .deployment(RelativePath::new("b/c.txt"))
+            .unwrap()
+            .unwrap()
+            .deployed_at;
+        fs::remove_dir_all(temp_dir.child("b")).unwrap();
+
+        let report = archive.resync_deployment().unwrap();
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(
+            report.changes[RelativePath::new("b/c.txt")].as_ref().unwrap(),
+            &SyncChange::Added
+        );
+        temp_dir.child("b/c.txt").assert("other data");
+        assert!(temp_dir.child("b/c.txt").symlink_metadata().unwrap().is_symlink());
+
+        let deployments = archive.deployments().unwrap();
+        assert_eq!(deployments.len(), 2);
+        assert_eq!(deployments[RelativePath::new("a.txt")].hash, test_data_hash);
+        assert_eq!(deployments[RelativePath::new("a.txt")].method, DeployMethod::Hardlink);
+        assert_eq!(deployments[RelativePath::new("b/c.txt")].method, DeployMethod::Symlink);
+        assert!(deployments[RelativePath::new("b/c.txt")].deployed_at >= deployed_at);
+        assert!(archive.deployment(RelativePath::new("d.txt")).unwrap().is_none());
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Named deployment targets, each deploying the archive's files to its own directory.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+use crate::{ConfigError, DeployError, DeployMethod, MediaArchive};
+
+pub(crate) const TARGETS_DIRECTORY: &str = "targets";
+
+impl MediaArchive {
+    /// Adds a named deployment target to the archive, or replaces the target with the same name.
+    ///
+    /// Names may only contain ASCII letters, digits, `-` and `_`.
+    #[tracing::instrument(skip(self), err)]
+    pub fn add_target(&mut self, name: &str, target: DeploymentTarget) -> Result<(), TargetError> {
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        {
+            return Err(TargetError::InvalidName(name.to_owned()));
+        }
+
+        let path = std::path::absolute(&target.path).map_err(TargetError::CreateDir)?;
+        fs::create_dir_all(&path).map_err(TargetError::CreateDir)?;
+
+        let mut config = self.config.clone();
+        config
+            .targets
+            .insert(name.to_owned(), DeploymentTarget { path, ..target });
+        self.set_config(config).map_err(TargetError::Config)?;
+
+        info!("added deployment target successfully");
+        Ok(())
+    }
+
+    /// Removes a named deployment target from the archive.
+    ///
+    /// Targets with deployed files can't be removed, the files must be undeployed first.
+    /// The deployment directory itself is left as it is.
+    #[tracing::instrument(skip(self), err)]
+    pub fn remove_target(&mut self, name: &str) -> Result<(), TargetError> {
+        let target = self.target(name)?;
+        if !target.deployments().map_err(TargetError::Deployments)?.is_empty() {
+            return Err(TargetError::NotEmpty(name.to_owned()));
+        }
+
+        let mut config = self.config.clone();
+        config.targets.remove(name);
+        self.set_config(config).map_err(TargetError::Config)?;
+
+        match fs::remove_file(target.deployment_state_path()) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(TargetError::Deployments(DeployError::State(err))),
+        }
+
+        info!("removed deployment target successfully");
+        Ok(())
+    }
+
+    /// Returns a handle to the archive that deploys files to a named deployment target.
+    ///
+    /// Every deployment operation on the returned archive, such as [`MediaArchive::deploy_file`]
+    /// or [`MediaArchive::status`], operates on the target's directory, and on the target's own record
+    /// of deployed files.
+    pub fn target(&self, name: &str) -> Result<MediaArchive, TargetError> {
+        let target = self
+            .config
+            .targets
+            .get(name)
+            .ok_or_else(|| TargetError::NotFound(name.to_owned()))?;
+
+        Ok(MediaArchive {
+            archive_path: self.archive_path.clone(),
+            deploy_path: Some(target.path.clone()),
+            config: self.config.clone(),
+            auto_deploy_methods: self.auto_deploy_methods.clone(),
+            target: Some(name.to_owned()),
+            lock: Arc::clone(&self.lock),
+            lock_timeout: self.lock_timeout,
+        })
+    }
+
+    /// Returns the method files should be deployed with, unless another one is explicitly wanted.
+    ///
+    /// This is the default method of the deployment target, if the archive is a handle to one,
+    /// or [`DeployMethod::Auto`] otherwise.
+    #[must_use]
+    pub fn default_deploy_method(&self) -> DeployMethod {
+        self.target
+            .as_ref()
+            .and_then(|name| self.config.targets.get(name))
+            .map_or(DeployMethod::Auto, |target| target.default_method)
+    }
+}
+
+/// A named directory the archive's files are deployed to.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeploymentTarget {
+    /// The directory the files are deployed to.
+    pub path: PathBuf,
+    /// The method files are deployed with by default, see [`MediaArchive::default_deploy_method`].
+    pub default_method: DeployMethod,
+}
+
+#[derive(Debug, Error)]
+pub enum TargetError {
+    #[error("failed to save configuration: {0}")]
+    Config(#[source] ConfigError),
+    #[error("failed to create deployment directory: {0}")]
+    CreateDir(#[source] io::Error),
+    #[error("failed to access the record of deployed files: {0}")]
+    Deployments(#[source] DeployError),
+    #[error("'{0}' is not a valid deployment target name")]
+    InvalidName(String),
+    #[error("deployment target '{0}' still has deployed files")]
+    NotEmpty(String),
+    #[error("deployment target '{0}' not found")]
+    NotFound(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use relative_path::RelativePath;
+
+    use crate::gc::tests::NO_GRACE_PERIOD;
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::DiskStructure;
+
+    #[test]
+    fn targets() {
+        let (temp_dir, mut archive) = temp_media_archive(DiskStructure::Deployable);
+        let targets_dir = TempDir::new().unwrap();
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        for (name, method) in [("family", DeployMethod::Symlink), ("phone", DeployMethod::Copy)] {
+            archive
+                .add_target(
+                    name,
+                    DeploymentTarget {
+                        path: targets_dir.child(name).to_path_buf(),
+                        default_method: method,
+                    },
+                )
+                .unwrap();
+        }
+        assert!(matches!(
+            archive.add_target(
+                "../escape",
+                DeploymentTarget {
+                    path: targets_dir.to_path_buf(),
+                    default_method: DeployMethod::Copy,
+                }
+            ),
+            Err(TargetError::InvalidName(_))
+        ));
+        assert!(matches!(archive.target("missing"), Err(TargetError::NotFound(_))));
+
+        let family = archive.target("family").unwrap();
+        assert_eq!(family.default_deploy_method(), DeployMethod::Symlink);
+        family
+            .deploy_file(&hash, RelativePath::new("a.txt"), family.default_deploy_method())
+            .unwrap();
+        targets_dir.child("family/a.txt").assert(TEST_DATA);
+        temp_dir.child("a.txt").assert(predicates::path::missing());
+
+        assert_eq!(archive.default_deploy_method(), DeployMethod::Auto);
+        assert!(archive.deployments().unwrap().is_empty());
+        assert!(archive.target("phone").unwrap().deployments().unwrap().is_empty());
+        assert_eq!(family.deployments().unwrap().len(), 1);
+
+        assert!(archive.collect_garbage(NO_GRACE_PERIOD).unwrap().removed.is_empty());
+
+        assert!(matches!(archive.remove_target("family"), Err(TargetError::NotEmpty(_))));
+        family.undeploy_all().unwrap();
+        archive.remove_target("family").unwrap();
+        assert!(matches!(archive.target("family"), Err(TargetError::NotFound(_))));
+
+        let archive = MediaArchive::open(temp_dir.to_path_buf(), DiskStructure::Deployable).unwrap();
+        assert_eq!(archive.config().targets.len(), 1);
+        assert_eq!(
+            archive.target("phone").unwrap().default_deploy_method(),
+            DeployMethod::Copy
+        );
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright © 2024 Joaquim Monteiro
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{hash_file, Hash, ListBlobsError, LockError, LockMode, MediaArchive, TEMP_DIRECTORY};
+
+const QUARANTINE_DIRECTORY: &str = "quarantine";
+
+impl MediaArchive {
+    /// Checks the integrity of the archive.
+    ///
+    /// Every stored file is hashed again and compared with the hash it's stored under.
+    /// Stored files that aren't read only, files in the store that don't belong there,
+    /// and temporary files left behind by interrupted operations are also reported.
+    ///
+    /// Temporary files are also created by operations in progress,
+    /// so they are only a sign of trouble if nothing else is using the archive.
+    #[tracing::instrument(skip(self), err)]
+    pub fn verify(&self, options: VerifyOptions) -> Result<VerifyReport, VerifyError> {
+        // Quarantining corrupt files removes them from the store.
+        let lock_mode = if options.quarantine {
+            LockMode::Exclusive
+        } else {
+            LockMode::Shared
+        };
+        let _lock = self.lock(lock_mode).map_err(VerifyError::Lock)?;
+
+        let quarantine_path = self.archive_path.join(QUARANTINE_DIRECTORY);
+        if options.quarantine {
+            fs::create_dir_all(&quarantine_path).map_err(VerifyError::CreateQuarantineDir)?;
+        }
+
+        let mut report = VerifyReport::default();
+        for result in self.blobs() {
+            let blob = match result {
+                Ok(blob) => blob,
+                Err(ListBlobsError::Misplaced { path, hash }) => {
+                    report.problems.push(VerifyProblem::Misplaced { path, hash });
+                    continue;
+                }
+                Err(ListBlobsError::StrayFile(path)) => {
+                    report.problems.push(VerifyProblem::StrayFile(path));
+                    continue;
+                }
+                Err(ListBlobsError::Metadata { path, source } | ListBlobsError::ReadDir { path, source }) => {
+                    report.problems.push(VerifyProblem::Unreadable { path, source });
+                    continue;
+                }
+            };
+
+            report.checked += 1;
+            let path = self.get_path_of_stored_file(&blob.hash);
+            let actual_hash = match hash_file(&path) {
+                Ok(actual_hash) => actual_hash,
+                Err(err) => {
+                    report.problems.push(VerifyProblem::Unreadable { path, source: err });
+                    continue;
+                }
+            };
+
+            if actual_hash != blob.hash {
+                warn!("stored file '{}' is corrupt", path.display());
+                let quarantined_path = options
+                    .quarantine
+                    .then(|| quarantine(&path, &quarantine_path, &blob.hash))
+                    .flatten();
+                report.problems.push(VerifyProblem::Corrupt {
+                    hash: blob.hash,
+                    actual_hash,
+                    quarantined_path,
+                });
+                continue;
+            }
+
+            match path.metadata() {
+                Ok(metadata) if !metadata.permissions().readonly() => {
+                    report.problems.push(VerifyProblem::NotReadOnly(blob.hash));
+                }
+                Ok(_) => (),
+                Err(err) => report.problems.push(VerifyProblem::Unreadable { path, source: err }),
+            }
+        }
+
+        let temp_dir = self.archive_path.join(TEMP_DIRECTORY);
+        match fs::read_dir(&temp_dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    match entry {
+                        Ok(entry) => report.problems.push(VerifyProblem::TempFile(entry.path())),
+                        Err(err) => report.problems.push(VerifyProblem::Unreadable {
+                            path: temp_dir.clone(),
+                            source: err,
+                        }),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => report.problems.push(VerifyProblem::Unreadable {
+                path: temp_dir,
+                source: err,
+            }),
+        }
+
+        info!(
+            "checked {} stored files, found {} problems",
+            report.checked,
+            report.problems.len()
+        );
+        Ok(report)
+    }
+}
+
+/// Moves a corrupt file into the quarantine directory, and returns its new path.
+fn quarantine(path: &Path, quarantine_path: &Path, hash: &Hash) -> Option<PathBuf> {
+    let hash = hash.to_hex();
+    let mut target_path = quarantine_path.join(hash.as_str());
+    let mut suffix = 0;
+    while target_path.symlink_metadata().is_ok() {
+        suffix += 1;
+        target_path = quarantine_path.join(format!("{}.{}", hash, suffix));
+    }
+
+    match fs::rename(path, &target_path) {
+        Ok(()) => {
+            info!("moved '{}' to '{}'", path.display(), target_path.display());
+            Some(target_path)
+        }
+        Err(err) => {
+            warn!("failed to move '{}' to quarantine: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VerifyOptions {
+    /// Whether to move corrupt files out of the store, into the archive's quarantine directory.
+    pub quarantine: bool,
+}
+
+/// The result of checking the integrity of an archive with [`MediaArchive::verify`].
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// The number of stored files that were checked.
+    pub checked: usize,
+    /// The problems that were found.
+    pub problems: Vec<VerifyProblem>,
+}
+
+#[derive(Debug, Error)]
+pub enum VerifyProblem {
+    #[error("stored file with hash '{hash}' is corrupt, its contents have hash '{actual_hash}'")]
+    Corrupt {
+        hash: Hash,
+        actual_hash: Hash,
+        /// The path the file was moved to, if it was quarantined.
+        quarantined_path: Option<PathBuf>,
+    },
+    #[error("stored file with hash '{hash}' is in the wrong location: '{path}'")]
+    Misplaced { path: PathBuf, hash: Hash },
+    #[error("stored file with hash '{0}' is not read only")]
+    NotReadOnly(Hash),
+    #[error("'{0}' is not a stored file")]
+    StrayFile(PathBuf),
+    #[error("leftover temporary file '{0}'")]
+    TempFile(PathBuf),
+    #[error("failed to read '{path}': {source}")]
+    Unreadable { path: PathBuf, source: io::Error },
+}
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("failed to create quarantine directory: {0}")]
+    CreateQuarantineDir(#[source] io::Error),
+    #[error("failed to lock the archive: {0}")]
+    Lock(#[source] LockError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assert_fs::prelude::*;
+    use predicates::prelude::*;
+
+    use crate::tests::{temp_media_archive, TEST_DATA};
+    use crate::DiskStructure;
+
+    #[test]
+    fn verify_healthy_archive() {
+        let (_temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        archive.store_reader(TEST_DATA.as_bytes()).unwrap();
+        archive.store_reader("other data".as_bytes()).unwrap();
+
+        let report = archive.verify(VerifyOptions::default()).unwrap();
+        assert_eq!(report.checked, 2);
+        assert!(report.problems.is_empty());
+    }
+
+    #[test]
+    fn verify_corrupt_file() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Deployable);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        let path = archive.get_path_of_stored_file(&hash);
+        let mut permissions = path.metadata().unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        fs::write(&path, "corrupted data").unwrap();
+
+        let report = archive.verify(VerifyOptions { quarantine: true }).unwrap();
+        assert_eq!(report.checked, 1);
+        let [VerifyProblem::Corrupt {
+            hash: reported_hash,
+            actual_hash,
+            quarantined_path: Some(quarantined_path),
+        }] = &report.problems[..]
+        else {
+            panic!("unexpected problems: {:?}", report.problems);
+        };
+        assert_eq!(*reported_hash, hash);
+        assert_eq!(*actual_hash, blake3::hash("corrupted data".as_bytes()));
+        assert!(quarantined_path.starts_with(temp_dir.child(".media-archive").child(QUARANTINE_DIRECTORY)));
+
+        assert!(!archive.contains(&hash));
+        temp_dir.child(quarantined_path).assert("corrupted data");
+    }
+
+    #[test]
+    fn verify_not_read_only_and_temp_file() {
+        let (temp_dir, archive) = temp_media_archive(DiskStructure::Bare);
+        let hash = archive.store_reader(TEST_DATA.as_bytes()).unwrap().hash();
+
+        let path = archive.get_path_of_stored_file(&hash);
+        let mut permissions = path.metadata().unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+
+        let temp_file = temp_dir.child(TEMP_DIRECTORY).child("store-leftover");
+        temp_file.touch().unwrap();
+
+        let report = archive.verify(VerifyOptions { quarantine: true }).unwrap();
+        assert_eq!(report.problems.len(), 2);
+        assert!(matches!(report.problems[0], VerifyProblem::NotReadOnly(h) if h == hash));
+        assert!(matches!(&report.problems[1], VerifyProblem::TempFile(p) if p == temp_file.path()));
+
+        temp_dir.child(QUARANTINE_DIRECTORY).assert(predicate::path::is_dir());
+        assert_eq!(fs::read_dir(temp_dir.child(QUARANTINE_DIRECTORY)).unwrap().count(), 0);
+    }
+}